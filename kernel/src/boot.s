@@ -11,14 +11,58 @@
 .set MAGIC,    0x1BADB002       /* 'magic number' lets bootloader find the header */
 .set CHECKSUM, -(MAGIC + FLAGS) /* checksum of above, to prove we are multiboot */
 
+/* Multiboot2 loaders look for their own header, so we provide both and let the loader choose. */
+.set MB2_MAGIC,    0xE85250D6     /* 'magic number' lets multiboot2 loaders find the header */
+.set MB2_ARCH,     0              /* i386 protected mode */
+.set MB2_LENGTH,   multiboot2_header_end - multiboot2_header_start
+.set MB2_CHECKSUM, 0x100000000 - (MB2_MAGIC + MB2_ARCH + MB2_LENGTH)
+
 .section .multiboot
 .align 4
 .long MAGIC
 .long FLAGS
 .long CHECKSUM
 
-/* Allocate some space for a small stack (16 byte aligned) */
-.section .bss.kernel
+/* Multiboot2 header and tags have to be 8 byte aligned */
+.align 8
+multiboot2_header_start:
+.long MB2_MAGIC
+.long MB2_ARCH
+.long MB2_LENGTH
+.long MB2_CHECKSUM
+/* module alignment tag: align loaded modules on page boundaries */
+.align 8
+.word 6
+.word 0
+.long 8
+/* end tag */
+.align 8
+.word 0
+.word 0
+.long 8
+multiboot2_header_end:
+
+/*
+Xen PVH note, which lets hypervisors like QEMU (-kernel) boot the ELF directly. The entry point
+is called in 32 bit protected mode without paging and with a pointer to the hvm_start_info
+structure in ebx.
+*/
+.set XEN_ELFNOTE_PHYS32_ENTRY, 18
+.set PVH_MAGIC, 0x336EC578      /* magic of the hvm_start_info structure */
+
+.section .note.Xen, "a", @note
+.align 4
+.long 4                         /* name size */
+.long 4                         /* descriptor size */
+.long XEN_ELFNOTE_PHYS32_ENTRY
+.asciz "Xen"
+.long pvh_start
+
+/* The kernel is linked into the last 2 GiB of the address space, see linker.ld. */
+.set KERNEL_OFFSET, 0xFFFFFFFF80000000
+
+/* Allocate some space for a small stack (16 byte aligned), only used in the higher half */
+.section .bss
 .align 16
 stack_bottom:
 .skip 65536 # 64 KiB
@@ -52,6 +96,10 @@ gdt_data:
 gdt_pointer:
 	.word GDT_SIZE - 1
 	.quad gdt
+/* same GDT, reloaded through the higher half so it survives dropping the identity map */
+gdt_pointer_high:
+	.word GDT_SIZE - 1
+	.quad gdt + KERNEL_OFFSET
 
 boot_data:
 mb_magic:
@@ -64,43 +112,91 @@ mb_data_ptr:
 /*
 Actual boot script. Since this is a multiboot kernel, we start in 32bit mode. Starting for that,
 we will:
-    - Setup 4 Level paging for the first two MiBs of memory
+    - Setup 4 Level paging for the first GiB of memory: identity mapped, at KERNEL_OFFSET and as
+      start of the physical memory window
+    - Add a fifth level on top if the CPU supports it
     - Enable long mode
     - Load 64bit GDT and jump into full long mode
+    - Jump into the higher half, where the kernel is linked
     - Setup the stack
     - Jump into rust code
+The code in .init is linked at its physical address and only runs with the identity map, which
+the kernel drops once it is in rust.
 */
 .section .init
+/* PVH entry, pass the start info magic like a multiboot loader would and continue as usual. */
+pvh_start:
+    mov $PVH_MAGIC, %eax
+    jmp start
+
 start:
-    /* preserve multiboot data */
+    /* preserve boot data (eax: magic, ebx: info pointer, for multiboot 1/2 and PVH) */
     mov %eax, (mb_magic)
     mov %ebx, (mb_data_ptr)
 setup_pages:
-    /* Clear the memory from 0x1000 to 0x2FFF */
+    /* Clear the memory from 0x1000 to 0x5FFF */
     mov $0x1000, %edi           # Set 0x1000 in destination register
     mov %edi, %cr3              # Set page directory pointer
     mov $0, %eax                # Empty EAX for stosd
-    mov $2048, %ecx             # Set rep counter (l = 4 bytes, so the size of two tables)
+    mov $5120, %ecx             # Set rep counter (l = 4 bytes, so the size of five tables)
     rep stosl                   # Clear memory
 
-    /* Setup a HUGE page for the first GiB */
+    /* Identity map the first GiB with a HUGE page (L4[0] -> L3 at 0x2000) */
     movl $0x2003, (0x1000)
     movl $0x83, (0x2000)
 
+    /* Map the same GiB at KERNEL_OFFSET (L4[511] -> L3 at 0x3000, L3[510]) */
+    movl $0x3003, (0x1000 + 511 * 8)
+    movl $0x83, (0x3000 + 510 * 8)
+
+    /* Start of the physical memory window (L4[256] -> L3 at 0x4000), extended by the kernel */
+    movl $0x4003, (0x1000 + 256 * 8)
+    movl $0x83, (0x4000)
+    /* The window is never executed, so set NX (bit 63). Only valid with EFER.NXE, which is on
+       before paging is. */
+    movl $0x80000000, (0x4000 + 4)
+
     /* Enable PAE (bit 5 of cr4) */
     mov %cr4, %eax
     or $(1 << 5), %eax
     mov %eax, %cr4
 
-    /* Enable LM (bit 8 of EFER) */
+    /* Use 5 level paging if the CPU supports it (CPUID leaf 7, bit 16 of ecx) */
+    mov $0, %eax
+    cpuid
+    cmp $7, %eax                # Leaf 7 has to exist
+    jb setup_long_mode
+    mov $7, %eax
+    mov $0, %ecx
+    cpuid
+    test $(1 << 16), %ecx
+    jz setup_long_mode
+
+    /*
+    L5 at 0x5000, with the first entry (identity map) and the last entry (higher half) both
+    pointing to the L4 above. All kernel addresses stay the same, they are just sign extended
+    from bit 56 instead of bit 47.
+    */
+    movl $0x1003, (0x5000)
+    movl $0x1003, (0x5000 + 511 * 8)
+    mov $0x5000, %eax
+    mov %eax, %cr3
+
+    /* Enable LA57 (bit 12 of cr4), only possible before paging is enabled */
+    mov %cr4, %eax
+    or $(1 << 12), %eax
+    mov %eax, %cr4
+
+setup_long_mode:
+    /* Enable LM (bit 8 of EFER) and NXE (bit 11 of EFER) */
     mov $0xC0000080, %ecx
     rdmsr
-    or $(1 << 8), %eax
+    or $(1 << 8 | 1 << 11), %eax
     wrmsr
 
-    /* Enable PG (bit 31 of CR0) */
+    /* Enable PG (bit 31 of CR0) and WP (bit 16 of CR0), so read-only pages apply to the kernel */
     mov %cr0, %eax
-    or $(1 << 31), %eax
+    or $(1 << 31 | 1 << 16), %eax
     mov %eax, %cr0
 
     lgdt (gdt_pointer)          # Load new GDT
@@ -117,11 +213,19 @@ start64:
     mov %ax, %gs
     mov %ax, %ss
 
+    /* The higher half is out of reach for a relative jump from here. */
+    movabs $start64_high, %rax
+    jmp *%rax
+
+.section .text
+start64_high:
+    lgdt (gdt_pointer_high)
+
     /* initialise stack */
     mov $stack_top, %rsp
 
-    /* rust call argument */
-    mov $boot_data, %rdi
+    /* rust call argument, boot_data is in .init, so we need its higher half alias */
+    movabs $(boot_data + KERNEL_OFFSET), %rdi
 
 	/*
     Now that we are in long mode and have a well defined stack, we can
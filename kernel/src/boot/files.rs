@@ -0,0 +1,111 @@
+/*
+Read-only files passed in by the boot loader as modules, e.g. `module2 /boot/init.bin init` in
+grub.cfg. The module string is kept as the file's command line and the file name of its first
+word is used to look the file up. The frame map keeps the module memory reserved, so the data
+stays valid for the lifetime of the kernel.
+*/
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+use macros::os_test;
+
+use crate::boot::info::BootModule;
+use crate::mem::address::PhysicalAddress;
+
+const MAX_BOOT_FILES: usize = 16;
+
+lazy_static! {
+    pub static ref BOOT_FILES: Mutex<BootFiles> = Mutex::new(BootFiles::new());
+}
+
+#[derive(Clone, Copy)]
+pub struct BootFile {
+    pub name: &'static str,
+    pub cmdline: &'static str,
+    pub data: &'static [u8],
+}
+
+pub struct BootFiles {
+    files: [Option<BootFile>; MAX_BOOT_FILES],
+    count: usize,
+}
+
+impl BootFile {
+    // None if the bootloader handed us a module that ends before it starts
+    fn from_module(module: BootModule) -> Option<BootFile> {
+        let size = module.end.checked_sub(module.start)?;
+        let path = module.string.split_whitespace().next().unwrap_or("");
+        Some(BootFile {
+            name: path.rsplit('/').next().unwrap_or(path),
+            cmdline: module.string,
+            data: unsafe { core::slice::from_raw_parts(PhysicalAddress::new(module.start).as_ptr(), size) },
+        })
+    }
+}
+
+impl BootFiles {
+    pub const fn new() -> BootFiles {
+        BootFiles { files: [None; MAX_BOOT_FILES], count: 0 }
+    }
+
+    pub fn init(&mut self, modules: &[BootModule]) {
+        for module in modules {
+            self.add(*module);
+        }
+
+        crate::logln!("[boot] Found {} boot files.", self.count);
+    }
+
+    fn add(&mut self, module: BootModule) {
+        if self.count == MAX_BOOT_FILES {
+            crate::logln!("[boot] Too many boot files, ignoring '{}'.", module.string);
+            return;
+        }
+
+        let file = match BootFile::from_module(module) {
+            Some(file) => file,
+            None => {
+                crate::logln!(
+                    "[boot] Ignoring boot file '{}', it ends (0x{:X}) before it starts (0x{:X}).",
+                    module.string,
+                    module.end,
+                    module.start
+                );
+                return;
+            }
+        };
+        crate::logln!("[boot] Boot file '{}' ({} bytes).", file.name, file.data.len());
+        self.files[self.count] = Some(file);
+        self.count += 1;
+    }
+
+    pub fn get(&self, name: &str) -> Option<BootFile> {
+        self.iter().find(|file| file.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BootFile> + '_ {
+        self.files[..self.count].iter().flatten().copied()
+    }
+}
+
+#[os_test]
+fn boot_files_lookup() {
+    static DATA: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+    let start = crate::mem::address::VirtualAddress::new(DATA.as_ptr() as usize).to_physical().data();
+    let mut files = BootFiles::new();
+    files.add(BootModule {
+        start,
+        end: start + DATA.len(),
+        string: "/boot/init.bin init --verbose",
+    });
+    // malformed, ends before it starts
+    files.add(BootModule { start, end: start - 1, string: "/boot/broken.bin" });
+
+    let file = files.get("init.bin").unwrap();
+    assert_eq!(file.data, &DATA);
+    assert_eq!(file.cmdline, "/boot/init.bin init --verbose");
+    assert!(files.get("init").is_none());
+    assert!(files.get("broken.bin").is_none());
+    assert_eq!(files.iter().count(), 1);
+}
@@ -0,0 +1,378 @@
+/*
+Boot information normalised from whichever protocol the kernel was started with (Multiboot,
+Multiboot2 or PVH). Everything after early boot should only look at this and never at the
+protocol specific structures, which are only parsed here.
+*/
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use crate::BootData;
+use crate::boot::memory_map;
+use crate::mem::MiB;
+use crate::mem::address::VirtualAddress;
+use crate::multiboot::{MULTIBOOT2_MAGIC, MULTIBOOT_MAGIC, Multiboot2Info, MultibootInfo};
+use crate::pvh::{PVH_MAGIC, StartInfo};
+
+pub(crate) const MAX_MEMORY_REGIONS: usize = 64;
+const MAX_MODULES: usize = 16;
+const MAX_BOOT_STRUCTURES: usize = 16;
+
+lazy_static! {
+    pub static ref BOOT_INFO: Mutex<BootInfo> = Mutex::new(BootInfo::new());
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BootProtocol {
+    None,
+    Multiboot,
+    Multiboot2,
+    Pvh,
+}
+
+// All three protocols use the E820 numbering for memory types.
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u32)]
+pub enum MemoryKind {
+    Unknown = 0,
+    Usable = 1,
+    Reserved = 2,
+    // ACPI tables, usable once they have been read
+    AcpiReclaimable = 3,
+    // ACPI non-volatile storage, has to be preserved across sleep states
+    AcpiNvs = 4,
+    Damaged = 5,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub length: usize,
+    pub kind: MemoryKind,
+}
+
+// Module loaded by the boot loader, `end` is the first address after the module.
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    pub start: usize,
+    pub end: usize,
+    pub string: &'static str,
+}
+
+// Physical memory that must not be handed out, `end` is the first address after it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Reservation {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FramebufferKind {
+    Indexed,
+    Rgb,
+    Text,
+    Unknown,
+}
+
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    pub address: usize,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub kind: FramebufferKind,
+}
+
+pub struct BootInfo {
+    pub protocol: BootProtocol,
+    pub kernel_start: usize,
+    pub kernel_end: usize,
+    pub cmdline: Option<&'static str>,
+    pub framebuffer: Option<Framebuffer>,
+    pub rsdp: Option<usize>,
+    memory_regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    region_count: usize,
+    modules: [BootModule; MAX_MODULES],
+    module_count: usize,
+    // boot loader memory the kernel still reads from, like the command line
+    boot_structures: [Reservation; MAX_BOOT_STRUCTURES],
+    boot_structure_count: usize,
+}
+
+impl From<u32> for MemoryKind {
+    fn from(kind: u32) -> MemoryKind {
+        match kind {
+            1 => MemoryKind::Usable,
+            2 => MemoryKind::Reserved,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::Damaged,
+            _ => MemoryKind::Unknown,
+        }
+    }
+}
+
+impl From<u8> for FramebufferKind {
+    fn from(kind: u8) -> FramebufferKind {
+        match kind {
+            0 => FramebufferKind::Indexed,
+            1 => FramebufferKind::Rgb,
+            2 => FramebufferKind::Text,
+            _ => FramebufferKind::Unknown,
+        }
+    }
+}
+
+impl BootInfo {
+    pub const fn new() -> BootInfo {
+        BootInfo {
+            protocol: BootProtocol::None,
+            kernel_start: 0,
+            kernel_end: 0,
+            cmdline: None,
+            framebuffer: None,
+            rsdp: None,
+            memory_regions: [MemoryRegion { base: 0, length: 0, kind: MemoryKind::Unknown }; MAX_MEMORY_REGIONS],
+            region_count: 0,
+            modules: [BootModule { start: 0, end: 0, string: "" }; MAX_MODULES],
+            module_count: 0,
+            boot_structures: [Reservation { start: 0, end: 0, name: "" }; MAX_BOOT_STRUCTURES],
+            boot_structure_count: 0,
+        }
+    }
+
+    pub unsafe fn init(&mut self, boot_data: &BootData) {
+        self.kernel_start = boot_data.kernel_start;
+        self.kernel_end = boot_data.kernel_end;
+
+        match boot_data.mb_magic {
+            MULTIBOOT_MAGIC => self.load_multiboot(MultibootInfo::load(boot_data.mb_info)),
+            MULTIBOOT2_MAGIC => self.load_multiboot2(Multiboot2Info::load(boot_data.mb_info)),
+            PVH_MAGIC => self.load_pvh(StartInfo::load(boot_data.mb_info)),
+            magic => panic!(
+                "Magic number does not match. Expected: 0x{:X}, 0x{:X} or 0x{:X}, Found: {:X}",
+                MULTIBOOT_MAGIC,
+                MULTIBOOT2_MAGIC,
+                PVH_MAGIC,
+                magic,
+            ),
+        }
+
+        let raw_regions = self.memory_regions;
+        self.region_count = memory_map::sanitise(&raw_regions[..self.region_count], &mut self.memory_regions);
+
+        crate::logln!(
+            "[boot] Booted via {:?} with {} memory regions and {} modules.",
+            self.protocol,
+            self.region_count,
+            self.module_count,
+        );
+    }
+
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        &self.memory_regions[..self.region_count]
+    }
+
+    pub fn memory_kind(&self, address: usize) -> Option<MemoryKind> {
+        self.memory_regions()
+            .iter()
+            .find(|region| address >= region.base && address - region.base < region.length)
+            .map(|region| region.kind)
+    }
+
+    pub fn memory_size(&self, kind: MemoryKind) -> usize {
+        self.memory_regions().iter().filter(|region| region.kind == kind).map(|region| region.length).sum()
+    }
+
+    // in the style of the E820 table Linux prints at boot
+    pub fn log_memory_map(&self) {
+        crate::logln!("[boot] Memory map:");
+        for region in self.memory_regions() {
+            crate::logln!(
+                "[boot]   0x{:016X} - 0x{:016X} {:?}",
+                region.base,
+                region.base + region.length - 1,
+                region.kind,
+            );
+        }
+        crate::logln!("[boot] {} MiB usable.", self.memory_size(MemoryKind::Usable) / MiB);
+    }
+
+    // whether all of `start..end` is RAM, usable or ACPI, so it may be cached
+    pub fn is_ram(&self, start: usize, end: usize) -> bool {
+        let mut covered = start;
+        // regions are sorted, see `memory_map::sanitise`
+        for region in self.memory_regions() {
+            let ram = matches!(region.kind, MemoryKind::Usable | MemoryKind::AcpiReclaimable | MemoryKind::AcpiNvs);
+            if ram && region.base <= covered && region.base + region.length > covered {
+                covered = region.base + region.length;
+            }
+        }
+        covered >= end
+    }
+
+    // end of the highest usable memory region
+    pub fn memory_end(&self) -> usize {
+        self.memory_regions()
+            .iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+            .map(|region| region.base + region.length)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn modules(&self) -> &[BootModule] {
+        &self.modules[..self.module_count]
+    }
+
+    // everything physical memory allocators have to keep their hands off
+    pub fn reservations(&self) -> impl Iterator<Item = Reservation> + Clone + '_ {
+        let fixed = [
+            // IVT, BIOS data, EBDA, VGA memory and the early page tables from boot.s
+            Reservation { start: 0, end: MiB, name: "low memory" },
+            Reservation { start: self.kernel_start, end: self.kernel_end, name: "kernel" },
+        ];
+        IntoIterator::into_iter(fixed)
+            .chain(self.modules().iter().map(|module| Reservation {
+                start: module.start,
+                end: module.end,
+                name: "module",
+            }))
+            .chain(self.boot_structures[..self.boot_structure_count].iter().copied())
+    }
+
+    fn load_multiboot(&mut self, info: &'static MultibootInfo) {
+        self.protocol = BootProtocol::Multiboot;
+        self.cmdline = info.cmdline();
+        self.reserve(info);
+        self.reserve(self.cmdline.unwrap_or(""));
+
+        let mut current = info.memory_map();
+        while let Some(pointer) = current {
+            self.add_memory_region(pointer.entry.base, pointer.entry.limit, pointer.entry.kind());
+            self.reserve(pointer.entry);
+            current = pointer.next();
+        }
+
+        self.reserve(info.module_entries());
+        for module in info.modules() {
+            self.add_module(module);
+            self.reserve(module.string);
+        }
+
+        self.framebuffer = info.framebuffer().map(|framebuffer| Framebuffer {
+            address: framebuffer.address as usize,
+            pitch: framebuffer.pitch,
+            width: framebuffer.width,
+            height: framebuffer.height,
+            bpp: framebuffer.bpp,
+            kind: FramebufferKind::from(framebuffer.kind),
+        });
+    }
+
+    fn load_multiboot2(&mut self, info: &'static Multiboot2Info) {
+        self.protocol = BootProtocol::Multiboot2;
+        self.cmdline = info.cmdline();
+        // all tags, strings included, are part of the info structure
+        let start = physical_address(info);
+        self.add_boot_structure(start, start + info.total_size as usize);
+
+        if let Some(memory_map) = info.memory_map() {
+            for entry in memory_map.entries() {
+                self.add_memory_region(entry.base, entry.length, entry.kind());
+            }
+        }
+
+        for module in info.modules() {
+            self.add_module(module);
+        }
+
+        self.framebuffer = info.framebuffer().map(|framebuffer| Framebuffer {
+            address: framebuffer.framebuffer_addr as usize,
+            pitch: framebuffer.framebuffer_pitch,
+            width: framebuffer.framebuffer_width,
+            height: framebuffer.framebuffer_height,
+            bpp: framebuffer.framebuffer_bpp,
+            kind: FramebufferKind::from(framebuffer.framebuffer_type),
+        });
+        self.rsdp = info.rsdp().map(|rsdp| rsdp.address());
+    }
+
+    fn load_pvh(&mut self, info: &'static StartInfo) {
+        self.protocol = BootProtocol::Pvh;
+        self.cmdline = info.cmdline();
+        self.reserve(info);
+        self.reserve(self.cmdline.unwrap_or(""));
+
+        if let Some(memory_map) = info.memory_map() {
+            for entry in memory_map {
+                self.add_memory_region(entry.base, entry.length, entry.kind());
+                self.reserve(entry);
+            }
+        }
+
+        self.reserve(info.module_entries());
+        for module in info.modules() {
+            self.add_module(module);
+            self.reserve(module.string);
+        }
+
+        self.rsdp = info.rsdp();
+    }
+
+    fn add_memory_region(&mut self, base: u64, length: u64, kind: MemoryKind) {
+        if self.region_count == MAX_MEMORY_REGIONS {
+            crate::logln!("[boot] Too many memory regions, ignoring 0x{:X} - 0x{:X}.", base, base + length);
+            return;
+        }
+        self.memory_regions[self.region_count] = MemoryRegion {
+            base: base as usize,
+            length: length as usize,
+            kind,
+        };
+        self.region_count += 1;
+    }
+
+    // `data` has to be boot loader memory, which is only reachable through the physical memory window
+    fn reserve<T: ?Sized>(&mut self, data: &'static T) {
+        let size = core::mem::size_of_val(data);
+        if size > 0 {
+            let start = physical_address(data);
+            self.add_boot_structure(start, start + size);
+        }
+    }
+
+    fn add_boot_structure(&mut self, start: usize, end: usize) {
+        // memory map entries and strings are usually packed together, so they can share an entry
+        let count = self.boot_structure_count;
+        if let Some(last) = self.boot_structures[..count].last_mut() {
+            if start <= last.end && end >= last.start {
+                last.start = last.start.min(start);
+                last.end = last.end.max(end);
+                return;
+            }
+        }
+        if count == MAX_BOOT_STRUCTURES {
+            crate::logln!("[boot] Too many boot structures, not reserving 0x{:X} - 0x{:X}.", start, end);
+            return;
+        }
+        self.boot_structures[count] = Reservation { start, end, name: "boot info" };
+        self.boot_structure_count += 1;
+    }
+
+    fn add_module(&mut self, module: BootModule) {
+        if self.module_count == MAX_MODULES {
+            crate::logln!("[boot] Too many modules, ignoring '{}'.", module.string);
+            return;
+        }
+        self.modules[self.module_count] = module;
+        self.module_count += 1;
+    }
+}
+
+fn physical_address<T: ?Sized>(data: &'static T) -> usize {
+    VirtualAddress::new(data as *const T as *const u8 as usize).to_physical().data()
+}
@@ -0,0 +1,138 @@
+/*
+Boot loaders hand over the memory map as they got it from the firmware: unsorted, sometimes with
+overlapping entries and with regions that don't start or end on a page boundary. `sanitise` turns
+it into sorted, page aligned regions that don't overlap, merging neighbours of the same kind.
+
+Where regions overlap the more restrictive kind wins: reserved > ACPI NVS > ACPI reclaimable >
+usable. Usable regions shrink to whole pages, all others grow to them, so a page that is only
+partly usable never counts as usable.
+*/
+
+use macros::os_test;
+
+use crate::boot::info::{MemoryKind, MemoryRegion, MAX_MEMORY_REGIONS};
+use crate::mem::frames::FrameSize;
+
+const PAGE_SIZE: usize = FrameSize::SMALL as usize;
+
+impl MemoryKind {
+    fn precedence(&self) -> u8 {
+        match self {
+            MemoryKind::Usable => 0,
+            MemoryKind::AcpiReclaimable => 1,
+            MemoryKind::AcpiNvs => 2,
+            MemoryKind::Reserved | MemoryKind::Unknown => 3,
+            MemoryKind::Damaged => 4,
+        }
+    }
+}
+
+// start and end of a region once it is page aligned
+fn aligned_bounds(region: &MemoryRegion) -> (usize, usize) {
+    let end = region.base.saturating_add(region.length);
+    if region.kind == MemoryKind::Usable {
+        (region.base.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1), end & !(PAGE_SIZE - 1))
+    } else {
+        (region.base & !(PAGE_SIZE - 1), end.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+    }
+}
+
+// writes the sanitised map to `output` and returns how many regions it has
+pub fn sanitise(regions: &[MemoryRegion], output: &mut [MemoryRegion]) -> usize {
+    assert!(regions.len() <= MAX_MEMORY_REGIONS, "Too many memory regions to sanitise.");
+
+    // the kind can only change where a region starts or ends
+    let mut boundaries = [0usize; 2 * MAX_MEMORY_REGIONS];
+    let mut boundary_count = 0;
+    for (start, end) in regions.iter().map(aligned_bounds) {
+        if start < end {
+            boundaries[boundary_count] = start;
+            boundaries[boundary_count + 1] = end;
+            boundary_count += 2;
+        }
+    }
+    let boundaries = &mut boundaries[..boundary_count];
+    boundaries.sort_unstable();
+
+    let mut count = 0;
+    for pair in boundaries.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let kind = regions
+            .iter()
+            .filter(|region| {
+                let (region_start, region_end) = aligned_bounds(region);
+                region_start <= start && region_end >= end
+            })
+            .map(|region| region.kind)
+            .max_by_key(MemoryKind::precedence);
+        let kind = match kind {
+            Some(kind) if start < end => kind,
+            // a hole, or the same boundary twice
+            _ => continue,
+        };
+
+        if count > 0 && output[count - 1].kind == kind && output[count - 1].base + output[count - 1].length == start {
+            output[count - 1].length += end - start;
+        } else if count < output.len() {
+            output[count] = MemoryRegion { base: start, length: end - start, kind };
+            count += 1;
+        } else {
+            crate::logln!("[boot] Too many memory regions, ignoring everything from 0x{:X}.", start);
+            break;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+fn region(base: usize, length: usize, kind: MemoryKind) -> MemoryRegion {
+    MemoryRegion { base, length, kind }
+}
+
+#[cfg(test)]
+fn sanitised(regions: &[MemoryRegion]) -> ([MemoryRegion; MAX_MEMORY_REGIONS], usize) {
+    let mut output = [region(0, 0, MemoryKind::Unknown); MAX_MEMORY_REGIONS];
+    let count = sanitise(regions, &mut output);
+    (output, count)
+}
+
+#[os_test]
+fn boot_memory_map_sort_and_align() {
+    let (output, count) = sanitised(&[
+        region(0x10_0000, 0x70_0000, MemoryKind::Usable),
+        region(0x40_0000, 0x1000, MemoryKind::Reserved),
+        region(0x7F_0000, 0x1_0800, MemoryKind::AcpiReclaimable),
+        region(0, 0x9_FC00, MemoryKind::Usable),
+    ]);
+
+    let expected = [
+        region(0, 0x9_F000, MemoryKind::Usable),
+        region(0x10_0000, 0x30_0000, MemoryKind::Usable),
+        region(0x40_0000, 0x1000, MemoryKind::Reserved),
+        region(0x40_1000, 0x3E_F000, MemoryKind::Usable),
+        region(0x7F_0000, 0x1_1000, MemoryKind::AcpiReclaimable),
+    ];
+    assert_eq!(count, expected.len());
+    for (region, expected) in output.iter().zip(expected.iter()) {
+        assert_eq!((region.base, region.length, region.kind), (expected.base, expected.length, expected.kind));
+    }
+}
+
+#[os_test]
+fn boot_memory_map_merge_and_precedence() {
+    let (output, count) = sanitised(&[
+        region(0x10_0000, 0x10_0000, MemoryKind::Usable),
+        region(0x20_0000, 0x10_0000, MemoryKind::Usable),
+        region(0x10_0000, 0x20_0000, MemoryKind::Usable),
+        region(0x30_0000, 0, MemoryKind::Reserved),
+        region(0xE000_0000, 0x1000, MemoryKind::AcpiNvs),
+        region(0xE000_0000, 0x800, MemoryKind::Reserved),
+        region(0xE000_0800, 0x1000, MemoryKind::AcpiReclaimable),
+    ]);
+
+    assert_eq!(count, 3);
+    assert_eq!((output[0].base, output[0].length, output[0].kind), (0x10_0000, 0x20_0000, MemoryKind::Usable));
+    // the reserved half page takes the whole page
+    assert_eq!((output[1].base, output[1].length, output[1].kind), (0xE000_0000, 0x1000, MemoryKind::Reserved));
+    assert_eq!((output[2].base, output[2].length, output[2].kind), (0xE000_1000, 0x1000, MemoryKind::AcpiReclaimable));
+}
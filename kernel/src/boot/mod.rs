@@ -0,0 +1,4 @@
+pub(crate) mod info;
+pub(crate) mod memory_map;
+pub(crate) mod options;
+pub(crate) mod files;
@@ -0,0 +1,166 @@
+/*
+Options are passed on the kernel command line as whitespace separated `key=value` pairs or bare
+flags, e.g. `log=debug heap=4M heap_max=256M heap_fit=best console=both frames=buddy quiet`.
+Unknown or invalid options are logged and otherwise ignored, so a typo never keeps the kernel from
+booting.
+*/
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+use macros::os_test;
+
+use crate::io::output::{ConsoleTarget, LogLevel};
+use crate::mem::allocator::FitPolicy;
+use crate::mem::frames::FrameAllocator;
+use crate::mem::{GiB, KiB, MiB};
+
+const MAX_FLAGS: usize = 8;
+
+lazy_static! {
+    pub static ref BOOT_OPTIONS: Mutex<BootOptions> = Mutex::new(BootOptions::new());
+}
+
+#[derive(Clone, Copy)]
+pub struct BootOptions {
+    pub log_level: LogLevel,
+    pub heap_size: usize,
+    // the heap grows on demand up to this size
+    pub heap_max: usize,
+    pub heap_fit: FitPolicy,
+    pub console: ConsoleTarget,
+    pub frame_allocator: FrameAllocator,
+    pub test_filter: Option<&'static str>,
+    flags: [&'static str; MAX_FLAGS],
+    flag_count: usize,
+}
+
+impl BootOptions {
+    pub fn new() -> BootOptions {
+        BootOptions {
+            log_level: LogLevel::Info,
+            heap_size: MiB,
+            heap_max: 64 * MiB,
+            heap_fit: FitPolicy::First,
+            console: ConsoleTarget::Vga,
+            frame_allocator: FrameAllocator::Bitmap,
+            test_filter: None,
+            flags: [""; MAX_FLAGS],
+            flag_count: 0,
+        }
+    }
+
+    pub fn init(&mut self, cmdline: Option<&'static str>) {
+        *self = BootOptions::parse(cmdline.unwrap_or(""));
+
+        crate::io::output::set_log_level(self.log_level);
+        crate::io::output::set_console(self.console);
+
+        crate::logln!(
+            "[boot] Options: log={:?}, heap={} KiB (max {} KiB, {:?} fit), console={:?}, frames={:?}.",
+            self.log_level,
+            self.heap_size / KiB,
+            self.heap_max / KiB,
+            self.heap_fit,
+            self.console,
+            self.frame_allocator,
+        );
+    }
+
+    pub fn parse(cmdline: &'static str) -> BootOptions {
+        let mut options = BootOptions::new();
+
+        for token in cmdline.split_whitespace() {
+            match token.split_once('=') {
+                Some((key, value)) => options.set_option(key, value),
+                None => options.set_flag(token),
+            }
+        }
+
+        options
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags[..self.flag_count].contains(&flag)
+    }
+
+    fn set_option(&mut self, key: &'static str, value: &'static str) {
+        let valid = match key {
+            "log" => LogLevel::parse(value).map(|level| self.log_level = level).is_some(),
+            "heap" => parse_size(value).map(|size| self.heap_size = size).is_some(),
+            "heap_max" => parse_size(value).map(|size| self.heap_max = size).is_some(),
+            "heap_fit" => FitPolicy::parse(value).map(|policy| self.heap_fit = policy).is_some(),
+            "console" => ConsoleTarget::parse(value).map(|console| self.console = console).is_some(),
+            "frames" => FrameAllocator::parse(value).map(|allocator| self.frame_allocator = allocator).is_some(),
+            "test" => {
+                self.test_filter = Some(value);
+                true
+            }
+            _ => {
+                crate::logln!("[boot] Ignoring unknown option '{}'.", key);
+                return;
+            }
+        };
+
+        if !valid {
+            crate::logln!("[boot] Ignoring invalid value '{}' for option '{}'.", value, key);
+        }
+    }
+
+    fn set_flag(&mut self, flag: &'static str) {
+        match flag {
+            "quiet" => self.log_level = LogLevel::Error,
+            "debug" => self.log_level = LogLevel::Debug,
+            // the boot loader may pass the kernel path as first argument
+            _ if flag.starts_with('/') => {}
+            _ if self.flag_count < MAX_FLAGS => {
+                self.flags[self.flag_count] = flag;
+                self.flag_count += 1;
+            }
+            _ => crate::logln!("[boot] Too many flags, ignoring '{}'.", flag),
+        }
+    }
+}
+
+// parses sizes like `4096`, `512K`, `4M` or `1G`
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+
+    let multiplier = match unit {
+        "" => 1,
+        "K" | "k" => KiB,
+        "M" | "m" => MiB,
+        "G" | "g" => GiB,
+        _ => return None,
+    };
+
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[os_test]
+fn boot_options_parse() {
+    let options = BootOptions::parse("/boot/journey_os.bin log=debug heap=4M heap_max=1G heap_fit=best console=both frames=buddy test=mem_ verbose");
+
+    assert_eq!(options.log_level, LogLevel::Debug);
+    assert_eq!(options.heap_size, 4 * MiB);
+    assert_eq!(options.heap_max, GiB);
+    assert_eq!(options.heap_fit, FitPolicy::Best);
+    assert_eq!(options.console, ConsoleTarget::Both);
+    assert_eq!(options.frame_allocator, FrameAllocator::Buddy);
+    assert_eq!(options.test_filter, Some("mem_"));
+    assert!(options.has_flag("verbose"));
+    assert!(!options.has_flag("/boot/journey_os.bin"));
+}
+
+#[os_test]
+fn boot_options_parse_invalid() {
+    let options = BootOptions::parse("heap=4X log=loud console= quiet");
+
+    assert_eq!(options.log_level, LogLevel::Error);
+    assert_eq!(options.heap_size, BootOptions::new().heap_size);
+    assert_eq!(options.console, ConsoleTarget::Vga);
+    assert_eq!(parse_size("512K"), Some(512 * KiB));
+    assert_eq!(parse_size("1G"), Some(GiB));
+}
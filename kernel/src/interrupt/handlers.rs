@@ -1,10 +1,5 @@
 use core::arch::asm;
 
-use crate::mem::address::{PhysicalAddress, VirtualAddress};
-use crate::mem::frames::{Frame, FRAME_MAP};
-use crate::mem::paging::mapper::map_frame;
-use crate::mem::paging::table::Table;
-
 #[repr(C)]
 pub struct ExceptionStackFrame {
     pub instruction_pointer: u64,
@@ -100,21 +95,18 @@ pub unsafe extern "x86-interrupt" fn page_fault(stack_frame: ExceptionStackFrame
     let address: usize;
     asm!("mov %cr2, {}", out(reg) address, options(att_syntax));
 
-    crate::logln!("Page fault: 0x{:X}. Address: 0x{:X}", error, address);
-
-    // page is not present and we're in kernel mode (bit 1 = present, bit 3: user)
-    if error & 0x1 == 0 && error & 0x4 == 0 {
-        let frame = &FRAME_MAP.lock().alloc_free();
-
-        map_frame(
-            frame,
-            &VirtualAddress::new(address >> 12 << 12),
-            Table::load_current(),
-        )
-    } else {
-        // TODO: implement userland paging
-        asm!("hlt");
-    }
+    // the kernel maps all of its memory up front, including the heap, so this is always a bug
+    // TODO: implement userland paging
+    panic!(
+        "Page fault at 0x{:X} (CR2), instruction: 0x{:X}. Error 0x{:X}: {} {} in {} mode{}.",
+        address,
+        stack_frame.instruction_pointer,
+        error,
+        if error & 1 << 4 != 0 { "instruction fetch" } else if error & 1 << 1 != 0 { "write" } else { "read" },
+        if error & 1 << 0 != 0 { "violating page protection" } else { "of a page that is not present" },
+        if error & 1 << 2 != 0 { "user" } else { "kernel" },
+        if error & 1 << 3 != 0 { ", reserved bit set in a page table" } else { "" },
+    );
 }
 
 // 0x10: FAULT
@@ -1,3 +1,4 @@
+use core::sync::atomic::{AtomicU8, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::io::vga::CONSOLE;
@@ -8,6 +9,64 @@ lazy_static! {
     pub static ref LOG_OUT: Mutex<Output> = Mutex::new(Output::new(&COM1));
 }
 
+pub static CONSOLE_AND_SERIAL: Mutex<ConsoleAndSerial> = Mutex::new(ConsoleAndSerial {});
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 0,
+    Info = 1,
+    Debug = 2,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConsoleTarget {
+    Serial,
+    Vga,
+    Both,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Option<LogLevel> {
+        match value {
+            "error" => Some(LogLevel::Error),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl ConsoleTarget {
+    pub fn parse(value: &str) -> Option<ConsoleTarget> {
+        match value {
+            "serial" => Some(ConsoleTarget::Serial),
+            "vga" => Some(ConsoleTarget::Vga),
+            "both" => Some(ConsoleTarget::Both),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_console(target: ConsoleTarget) {
+    let mut std_out = STD_OUT.lock();
+    match target {
+        ConsoleTarget::Serial => std_out.set(&COM1),
+        ConsoleTarget::Vga => std_out.set(&CONSOLE),
+        ConsoleTarget::Both => std_out.set(&CONSOLE_AND_SERIAL),
+    }
+}
+
 pub trait StdOutWriter {
     fn write(&mut self, s: &str);
 }
@@ -17,6 +76,16 @@ pub struct Output {
     writer: &'static Mutex<dyn StdOutWriter + Send>,
 }
 
+// mirrors standard output to the serial port
+pub struct ConsoleAndSerial {}
+
+impl StdOutWriter for ConsoleAndSerial {
+    fn write(&mut self, s: &str) {
+        CONSOLE.lock().write(s);
+        COM1.lock().write_str(s);
+    }
+}
+
 impl core::fmt::Write for Output {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.writer.lock().write(s);
@@ -58,6 +127,24 @@ macro_rules! log {
 #[macro_export]
 macro_rules! logln {
     () => ($crate::kprint!("\n"));
+    ($($arg:tt)*) => (
+        if $crate::io::output::log_enabled($crate::io::output::LogLevel::Info) {
+            $crate::log!("[kernel] {}\n", format_args!($($arg)*))
+        }
+    );
+}
+
+#[macro_export]
+macro_rules! debugln {
+    ($($arg:tt)*) => (
+        if $crate::io::output::log_enabled($crate::io::output::LogLevel::Debug) {
+            $crate::log!("[kernel] {}\n", format_args!($($arg)*))
+        }
+    );
+}
+
+#[macro_export]
+macro_rules! errorln {
     ($($arg:tt)*) => ($crate::log!("[kernel] {}\n", format_args!($($arg)*)));
 }
 
@@ -1,5 +1,7 @@
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::boot::info::{BootInfo, FramebufferKind};
+use crate::mem::address::PhysicalAddress;
 
 const VGA_BUFFER_ADDR: usize = 0xb8000;
 const COLUMNS: usize = 80;
@@ -77,12 +79,28 @@ impl Console {
         let mut console = Console {
             current_color: ColorCode::new(Color::White, Color::Black),
             cursor: Cursor { row: 0, column: 0 },
-            buffer: unsafe { &mut *(VGA_BUFFER_ADDR as *mut Buffer) },
+            buffer: unsafe { &mut *PhysicalAddress::new(VGA_BUFFER_ADDR).as_mut_ptr::<Buffer>() },
         };
         console.clear();
         console
     }
 
+    // only text mode framebuffers can be used, graphical ones are left alone for now
+    pub fn init(&mut self, boot_info: &BootInfo) {
+        match boot_info.framebuffer {
+            Some(framebuffer) if framebuffer.kind == FramebufferKind::Text => {
+                self.buffer = unsafe { &mut *PhysicalAddress::new(framebuffer.address).as_mut_ptr::<Buffer>() };
+                self.clear();
+            }
+            Some(framebuffer) => crate::logln!(
+                "[vga] Framebuffer at 0x{:X} is not in text mode ({:?}).",
+                framebuffer.address,
+                framebuffer.kind,
+            ),
+            None => {}
+        }
+    }
+
     pub fn write(&mut self, string: &str) {
         for char in string.bytes() {
             self.write_byte(char);
@@ -13,14 +13,14 @@ extern crate alloc;
 use core::arch::asm;
 use core::arch::global_asm;
 use core::panic::PanicInfo;
-use crate::mem::KiB;
-use crate::multiboot::MultibootInfo;
 
 #[cfg(test)]
 use crate::os_test::test_panic;
 
 mod io;
+mod boot;
 mod multiboot;
+mod pvh;
 mod mem;
 mod util;
 mod os_test;
@@ -30,7 +30,7 @@ global_asm!(include_str!("boot.s"), options(att_syntax));
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    logln!("{}", info);
+    errorln!("{}", info);
 
     #[cfg(test)]
     test_panic();
@@ -43,25 +43,49 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
+// Handed over by boot.s, `mb_magic` tells which boot protocol `mb_info` belongs to.
 #[repr(C, packed)]
 pub struct BootData {
     mb_magic: u32,
-    mb_info: &'static MultibootInfo,
+    mb_info: usize,
     kernel_start: usize,
     kernel_end: usize,
 }
 
 #[no_mangle]
 pub unsafe extern "cdecl" fn kernel_main(boot_data: &BootData) -> ! {
-    if boot_data.mb_magic != 0x2BADB002 {
-        panic!("Magic number does not match. Expected: 0x2BADB002, Found: {:X}", { boot_data.mb_magic });
-    } else {
+    {
+        mem::paging::detect_paging_mode();
+        // everything the kernel needs is reachable through the higher half from here on
+        mem::paging::mapper::unmap_identity();
+        // Vec and Box work from here on, the heap takes over once it is set up
+        mem::bump::EARLY_ALLOCATOR.lock().init_arena();
+
+        // BOOT_INFO is only locked for single steps, so init code is free to read it as well
+        {
+            let mut boot_info = boot::info::BOOT_INFO.lock();
+            boot_info.init(boot_data);
+            io::vga::CONSOLE.lock().init(&boot_info);
+            boot::options::BOOT_OPTIONS.lock().init(boot_info.cmdline);
+        }
+        let options = *boot::options::BOOT_OPTIONS.lock();
+
         println!("Booting Journey OS 0.1.0");
-    }
+        boot::info::BOOT_INFO.lock().log_memory_map();
 
-    interrupt::idt::INTERRUPTS.lock().init();
-    mem::frames::FRAME_MAP.lock().init(boot_data);
-    mem::allocator::ALLOCATOR.lock().init(0x4000_0000_0000, 10 * KiB);
+        interrupt::idt::INTERRUPTS.lock().init();
+        mem::frames::FRAME_MAP.lock().init(&boot::info::BOOT_INFO.lock());
+        mem::paging::kernel::map_physical_memory(&boot::info::BOOT_INFO.lock());
+        if options.frame_allocator == mem::frames::FrameAllocator::Buddy {
+            mem::frames::FRAME_MAP.lock().enable_buddy();
+        }
+        mem::paging::kernel::remap_kernel();
+        mem::paging::kernel::create_kernel_tables();
+        boot::files::BOOT_FILES.lock().init(boot::info::BOOT_INFO.lock().modules());
+        mem::allocator::ALLOCATOR.lock().set_policy(options.heap_fit);
+        mem::allocator::ALLOCATOR.lock().init(mem::allocator::HEAP_START, options.heap_size, options.heap_max);
+        mem::allocator::use_heap();
+    }
 
     #[cfg(test)]
     test_main();
@@ -1,35 +1,232 @@
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use macros::os_test;
 
+use crate::mem::{GiB, KERNEL_OFFSET, PHYSICAL_OFFSET};
+use crate::mem::paging::five_level_paging;
+
 const L1_SHIFT: u8 = 12;
 const L2_SHIFT: u8 = 21;
 const L3_SHIFT: u8 = 30;
 const L4_SHIFT: u8 = 39;
+const L5_SHIFT: u8 = 48;
 const INDEX_MASK: usize = 0x1FF; // 9 lowest bits
 
+// end of the physical memory window, boot.s maps the first GiB
+static PHYSICAL_WINDOW_END: AtomicUsize = AtomicUsize::new(GiB);
+
+pub fn set_physical_window_end(end: usize) {
+    PHYSICAL_WINDOW_END.store(end, Ordering::Relaxed);
+}
+
+const PHYSICAL_ADDRESS_BITS: u32 = 52;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PhysicalAddress(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VirtualAddress(usize);
 
 impl PhysicalAddress {
     pub fn new(address: usize) -> PhysicalAddress {
-        return PhysicalAddress { 0: address }
+        PhysicalAddress::try_new(address).expect("Physical address wider than 52 bits.")
+    }
+
+    pub fn try_new(address: usize) -> Option<PhysicalAddress> {
+        if address >> PHYSICAL_ADDRESS_BITS == 0 {
+            Some(PhysicalAddress(address))
+        } else {
+            None
+        }
     }
 
     pub fn data(&self) -> usize {
         self.0
     }
+
+    pub fn align_up(&self, align: usize) -> PhysicalAddress {
+        PhysicalAddress::new(align_up(self.0, align))
+    }
+
+    pub fn align_down(&self, align: usize) -> PhysicalAddress {
+        PhysicalAddress(align_down(self.0, align))
+    }
+
+    pub fn is_aligned(&self, align: usize) -> bool {
+        self.0 % align == 0
+    }
+
+    // address of the physical memory window, the kernel image is reachable through it as well
+    pub fn to_virtual(&self) -> VirtualAddress {
+        assert!(
+            self.0 < PHYSICAL_WINDOW_END.load(Ordering::Relaxed),
+            "Physical address 0x{:X} is not mapped.",
+            self.0,
+        );
+        VirtualAddress::new(self.0 + PHYSICAL_OFFSET)
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.to_virtual().data() as *const T
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.to_virtual().data() as *mut T
+    }
 }
 
 impl VirtualAddress {
     pub fn new(address: usize) -> VirtualAddress {
-        return VirtualAddress { 0: address }
+        VirtualAddress::try_new(address)
+            .unwrap_or_else(|| panic!("Virtual address 0x{:X} is not canonical.", address))
+    }
+
+    // the unused upper bits have to be copies of the highest used one, which depends on the paging mode
+    pub fn try_new(address: usize) -> Option<VirtualAddress> {
+        let bits = if five_level_paging() { 57 } else { 48 };
+        let upper = (address as isize) >> (bits - 1);
+        if upper == 0 || upper == -1 {
+            Some(VirtualAddress(address))
+        } else {
+            None
+        }
     }
 
     pub fn data(&self) -> usize {
         self.0
     }
 
+    pub fn align_up(&self, align: usize) -> VirtualAddress {
+        VirtualAddress::new(align_up(self.0, align))
+    }
+
+    pub fn align_down(&self, align: usize) -> VirtualAddress {
+        VirtualAddress::new(align_down(self.0, align))
+    }
+
+    pub fn is_aligned(&self, align: usize) -> bool {
+        self.0 % align == 0
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    // only valid for the kernel image and the physical memory window
+    pub fn to_physical(&self) -> PhysicalAddress {
+        if self.0 >= KERNEL_OFFSET {
+            return PhysicalAddress::new(self.0 - KERNEL_OFFSET);
+        }
+        let window_end = PHYSICAL_OFFSET + PHYSICAL_WINDOW_END.load(Ordering::Relaxed);
+        assert!(
+            self.0 >= PHYSICAL_OFFSET && self.0 < window_end,
+            "Virtual address 0x{:X} is not in a linear kernel mapping.",
+            self.0,
+        );
+        PhysicalAddress::new(self.0 - PHYSICAL_OFFSET)
+    }
+
+    pub fn l5_index(&self) -> usize { self.0 >> L5_SHIFT & INDEX_MASK }
     pub fn l4_index(&self) -> usize { self.0 >> L4_SHIFT & INDEX_MASK }
     pub fn l3_index(&self) -> usize { self.0 >> L3_SHIFT & INDEX_MASK }
     pub fn l2_index(&self) -> usize { self.0 >> L2_SHIFT & INDEX_MASK }
     pub fn l1_index(&self) -> usize { self.0 >> L1_SHIFT & INDEX_MASK }
 }
+
+// `align` has to be a power of two
+fn align_up(address: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "Alignment has to be a power of two.");
+    address.checked_add(align - 1).expect("Aligned address overflows.") & !(align - 1)
+}
+
+fn align_down(address: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "Alignment has to be a power of two.");
+    address & !(align - 1)
+}
+
+macro_rules! address_arithmetic {
+    ($type:ident) => {
+        impl Add<usize> for $type {
+            type Output = $type;
+
+            fn add(self, offset: usize) -> $type {
+                $type::new(self.0.checked_add(offset).expect("Address overflow."))
+            }
+        }
+
+        impl AddAssign<usize> for $type {
+            fn add_assign(&mut self, offset: usize) {
+                *self = *self + offset;
+            }
+        }
+
+        impl Sub<usize> for $type {
+            type Output = $type;
+
+            fn sub(self, offset: usize) -> $type {
+                $type::new(self.0.checked_sub(offset).expect("Address underflow."))
+            }
+        }
+
+        impl SubAssign<usize> for $type {
+            fn sub_assign(&mut self, offset: usize) {
+                *self = *self - offset;
+            }
+        }
+
+        // distance between two addresses
+        impl Sub<$type> for $type {
+            type Output = usize;
+
+            fn sub(self, other: $type) -> usize {
+                self.0.checked_sub(other.0).expect("Address underflow.")
+            }
+        }
+    };
+}
+
+address_arithmetic!(PhysicalAddress);
+address_arithmetic!(VirtualAddress);
+
+#[os_test]
+fn mem_address_kernel_mapping() {
+    static VALUE: u64 = 0x1234_5678;
+
+    let virtual_address = VirtualAddress::new(&VALUE as *const u64 as usize);
+    let physical = virtual_address.to_physical();
+    assert!(physical.data() < GiB);
+    // the same memory, read through the physical memory window
+    assert_eq!(unsafe { *physical.as_ptr::<u64>() }, 0x1234_5678);
+    assert_eq!(physical.to_virtual().to_physical().data(), physical.data());
+}
+
+#[os_test]
+fn mem_address_indices() {
+    let address = VirtualAddress::new(0xFFFF_8000_0000_0000 | 1 << 39 | 2 << 30 | 3 << 21 | 4 << 12 | 5);
+    assert_eq!(address.l5_index(), 0x1FF);
+    assert_eq!(address.l4_index(), 0x101);
+    assert_eq!(address.l3_index(), 2);
+    assert_eq!(address.l2_index(), 3);
+    assert_eq!(address.l1_index(), 4);
+}
+
+#[os_test]
+fn mem_address_checks() {
+    assert!(VirtualAddress::try_new(0x0000_7FFF_FFFF_F000).is_some());
+    assert!(VirtualAddress::try_new(0xFFFF_8000_0000_0000).is_some());
+    if !five_level_paging() {
+        assert!(VirtualAddress::try_new(0x0000_8000_0000_0000).is_none());
+    }
+    assert!(VirtualAddress::try_new(0x1234_0000_0000_0000).is_none());
+    assert!(PhysicalAddress::try_new(1 << 52).is_none());
+
+    let address = PhysicalAddress::new(0x1234);
+    assert_eq!(address.align_up(0x1000).data(), 0x2000);
+    assert_eq!(address.align_down(0x1000).data(), 0x1000);
+    assert_eq!((address + 0x10) - address, 0x10);
+    assert!(address.align_up(0x1000).is_aligned(0x1000));
+}
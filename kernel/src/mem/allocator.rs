@@ -2,19 +2,87 @@ use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
 use core::borrow::BorrowMut;
 use core::ops::{Deref, DerefMut, Index};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use macros::os_test;
 
 use crate::mem::address::VirtualAddress;
+use crate::mem::bump::{in_early_arena, EARLY_ALLOCATOR};
 use crate::mem::frames::FrameSize;
-use crate::mem::paging::mapper::map_frame;
+use crate::mem::paging::entry::PageFlags;
+use crate::mem::paging::mapper::{try_map_frame, unmap};
 use crate::mem::frames::FRAME_MAP;
-use crate::mem::align_address;
+use crate::mem::page::{Page, Size4KiB};
 use crate::mem::paging::table::Table;
+use crate::mem::slab;
 use crate::util::locked::Locked;
 
-#[global_allocator]
+// the heap lives in the upper half, so the lower half stays free for user address spaces
+pub const HEAP_START: usize = 0xFFFF_C000_0000_0000;
+
 pub static ALLOCATOR: Locked<LinkedHeap> = Locked::new(LinkedHeap::new());
 
+#[global_allocator]
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+static HEAP_READY: AtomicBool = AtomicBool::new(false);
+
+// everything the kernel allocator hands out once the heap is ready, slab allocations included
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+
+// Allocates from the early bump allocator until `use_heap` is called, and from the heap after that.
+// Small allocations on the heap go through the slab caches.
+pub struct KernelAllocator;
+
+// Early allocations stay where they are, only new ones come from the heap.
+pub fn use_heap() {
+    HEAP_READY.store(true, Ordering::Release);
+
+    let early = EARLY_ALLOCATOR.lock();
+    crate::logln!(
+        "[allocator] Switched to the heap, {} early allocations remain ({} bytes).",
+        early.allocations(),
+        early.used(),
+    );
+}
+
+// A snapshot of the kernel heap, see `heap_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    // mapped bytes
+    pub size: usize,
+    // Bytes handed out and how often. `heap_stats` counts every allocation of the kernel allocator,
+    // `LinkedHeap::stats` only the ones that went to that heap, where a slab is one allocation.
+    pub allocated: usize,
+    pub peak: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub free_regions: usize,
+    pub free_bytes: usize,
+    pub largest_free: usize,
+    // percentage of free bytes outside of the largest free region
+    pub fragmentation: usize,
+    // bytes in slabs, and how much of it is handed out
+    pub slab_size: usize,
+    pub slab_used: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let mut stats = ALLOCATOR.lock().stats();
+    stats.allocated = ALLOCATED.load(Ordering::Relaxed);
+    stats.peak = PEAK.load(Ordering::Relaxed);
+    stats.allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    stats.frees = FREES.load(Ordering::Relaxed);
+    for cache in slab::SIZE_CLASSES.iter() {
+        let cache = cache.lock();
+        stats.slab_size += cache.objects() * cache.object_size();
+        stats.slab_used += cache.used() * cache.object_size();
+    }
+    stats
+}
+
 const MEMORY_NODE_SIZE: usize = core::mem::size_of::<MemoryNode>();
 const MEMORY_NODE_ALIGN: usize = core::mem::align_of::<MemoryNode>();
 
@@ -38,72 +106,293 @@ impl MemoryNode {
 }
 
 pub struct Region {
-    node: &'static MemoryNode,
+    start: usize,
+    end: usize,
     alloc_start: usize,
     alloc_end: usize,
 }
 
 impl Region {
-    fn new(node: &'static MemoryNode, alloc_start: usize, alloc_end: usize) -> Region {
-        Region { node, alloc_start, alloc_end }
+    fn new(start: usize, end: usize, alloc_start: usize, alloc_end: usize) -> Region {
+        Region { start, end, alloc_start, alloc_end }
     }
 }
 
+// which free region an allocation is taken from, chosen with the `heap_fit` boot option
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FitPolicy {
+    // the region with the lowest address
+    First,
+    // the smallest region
+    Best,
+}
+
+impl FitPolicy {
+    pub fn parse(value: &str) -> Option<FitPolicy> {
+        match value {
+            "first" => Some(FitPolicy::First),
+            "best" => Some(FitPolicy::Best),
+            _ => None,
+        }
+    }
+}
+
+// The free list is sorted by address and neighbouring regions are merged as soon as they are
+// freed, so there are never two free regions next to each other.
+//
+// Only the pages up to `limit` are mapped. The heap grows page by page when it runs out of space,
+// until it reaches `max_limit`, and `shrink` gives free pages at the end back.
 pub struct LinkedHeap {
     list: MemoryNode,
+    policy: FitPolicy,
+    start: usize,
     limit: usize,
+    min_limit: usize,
+    max_limit: usize,
+    allocated: usize,
+    peak: usize,
+    allocations: usize,
+    frees: usize,
 }
 
 impl LinkedHeap {
     pub const fn new() -> LinkedHeap {
-        LinkedHeap { list: MemoryNode::new(0), limit: 0 }
+        LinkedHeap {
+            list: MemoryNode::new(0),
+            policy: FitPolicy::First,
+            start: 0,
+            limit: 0,
+            min_limit: 0,
+            max_limit: 0,
+            allocated: 0,
+            peak: 0,
+            allocations: 0,
+            frees: 0,
+        }
+    }
+
+    pub unsafe fn init(&mut self, start: usize, size: usize, max_size: usize) {
+        self.start = start;
+        self.limit = start;
+        self.max_limit = start + max_size.max(size);
+        assert!(self.grow(size), "Failed to map the initial kernel heap.");
+        self.min_limit = self.limit;
+
+        crate::logln!(
+            "[allocator] Built 0x{:X} byte kernel heap at 0x{:X}, it can grow to 0x{:X} bytes ({:?} fit).",
+            self.size(),
+            start,
+            self.max_limit - start,
+            self.policy,
+        );
+    }
+
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
+    // mapped bytes
+    pub fn size(&self) -> usize {
+        self.limit - self.start
+    }
+
+    // walks the free list, the slab fields are left empty
+    pub fn stats(&self) -> HeapStats {
+        let (mut free_regions, mut free_bytes, mut largest_free) = (0, 0, 0);
+        let mut current = &self.list;
+        while let Some(node) = current.next.as_deref() {
+            free_regions += 1;
+            free_bytes += node.size;
+            largest_free = largest_free.max(node.size);
+            current = node;
+        }
+
+        HeapStats {
+            size: self.size(),
+            allocated: self.allocated,
+            peak: self.peak,
+            allocations: self.allocations,
+            frees: self.frees,
+            free_regions,
+            free_bytes,
+            largest_free,
+            fragmentation: if free_bytes == 0 { 0 } else { 100 - largest_free * 100 / free_bytes },
+            slab_size: 0,
+            slab_used: 0,
+        }
     }
 
-    pub unsafe fn init(&mut self, start: usize, size: usize) {
-        self.limit = start + size;
-        self.free_region(start, size);
+    pub fn dump_free_list(&self) {
+        crate::logln!("[allocator] Free list of the heap at 0x{:X}:", self.start);
+        let mut current = &self.list;
+        while let Some(node) = current.next.as_deref() {
+            crate::logln!("[allocator]   0x{:X} - 0x{:X} (0x{:X} bytes)", node.start_address(), node.end_address(), node.size);
+            current = node;
+        }
+    }
+
+    // maps at least `size` more bytes at the end of the heap and adds them to the free list
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        let first = Page::<Size4KiB>::containing_address(VirtualAddress::new(self.limit));
+        let end = Page::<Size4KiB>::containing_address(VirtualAddress::new(self.limit + size).align_up(first.size()));
+        if end.start_address().data() > self.max_limit {
+            return false;
+        }
 
-        crate::logln!("[allocator] Built 0x{:X} byte kernel heap at 0x{:X}.", size, start);
+        for page in Page::range(first, end) {
+            // the frame map is locked again below, so the guard must not live on in a match
+            let frame = FRAME_MAP.lock().try_alloc_free();
+            let mapped = match frame {
+                Some(frame) => {
+                    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+                    let mapped = crate::with_active_root!(|root| try_map_frame(&frame, &page.start_address(), flags, root));
+                    if !mapped {
+                        FRAME_MAP.lock().free(frame);
+                    }
+                    mapped
+                }
+                None => false,
+            };
+            if !mapped {
+                crate::logln!("[allocator] Out of physical memory, the heap can't grow.");
+                unmap_pages(first, page);
+                return false;
+            }
+        }
+
+        let old_limit = self.limit;
+        self.limit = end.start_address().data();
+        self.free_region(old_limit, self.limit - old_limit);
+        crate::debugln!("[allocator] Heap grew to 0x{:X} bytes.", self.size());
+        true
     }
 
+    // Unmaps the free pages at the end of the heap, but never goes below the initial size. Returns
+    // the number of bytes given back.
+    pub unsafe fn shrink(&mut self) -> usize {
+        let limit = self.limit;
+        let mut parent = &mut self.list as *mut MemoryNode;
+        while let Some(node) = (*parent).next.as_deref_mut() {
+            if node.end_address() == limit {
+                break;
+            }
+            parent = node;
+        }
+        let node = match (*parent).next.take() {
+            Some(node) => node,
+            None => return 0,
+        };
+
+        // whatever is left of the node has to hold a node again
+        let node_start = node.start_address();
+        let mut new_limit = VirtualAddress::new(node_start).align_up(FrameSize::SMALL as usize).data();
+        if new_limit != node_start && new_limit - node_start < MEMORY_NODE_SIZE {
+            new_limit += FrameSize::SMALL as usize;
+        }
+        let new_limit = new_limit.max(self.min_limit);
+        if new_limit >= limit {
+            (*parent).next = Some(node);
+            return 0;
+        }
+        (*parent).next = node.next.take();
+
+        unmap_pages(
+            Page::containing_address(VirtualAddress::new(new_limit)),
+            Page::containing_address(VirtualAddress::new(limit)),
+        );
+
+        self.limit = new_limit;
+        if new_limit > node_start {
+            self.free_region(node_start, new_limit - node_start);
+        }
+        crate::debugln!("[allocator] Heap shrunk to 0x{:X} bytes.", self.size());
+        limit - new_limit
+    }
+
+    // inserts the region in address order and merges it with the free regions next to it
     pub unsafe fn free_region(&mut self, start: usize, size: usize) {
         assert!(size >= MEMORY_NODE_SIZE, "Freed region too small for memory node.");
         assert_eq!(start % MEMORY_NODE_ALIGN, 0, "Freed region is not properly aligned");
-        assert!(start + size <= self.limit, "Freed region exceeds heap limit.");
+        assert!(start >= self.start && start + size <= self.limit, "Freed region outside of the heap.");
 
-        let mut node = MemoryNode::new(size);
-        node.next = self.list.next.take();
-        let ptr = start as *mut MemoryNode;
-        ptr.write(node);
-        self.list.next = Some(&mut *ptr);
-    }
+        let head = &mut self.list as *mut MemoryNode;
+        let mut parent = head;
+        while let Some(node) = (*parent).next.as_deref_mut() {
+            if node.start_address() > start {
+                break;
+            }
+            parent = node;
+        }
 
-    pub unsafe fn find_region(&mut self, size: usize, align: usize, merged: bool) -> Option<Region> {
-        let mut current = &mut self.list;
+        let mut size = size;
+        let mut next = (*parent).next.take();
+        if let Some(node) = next.as_deref() {
+            assert!(start + size <= node.start_address(), "Freed region 0x{:X} is already free.", start);
+        }
+        if parent != head {
+            assert!((*parent).end_address() <= start, "Freed region 0x{:X} is already free.", start);
+        }
 
-        while let Some(ref mut node) = current.next {
-            if let Ok((alloc_start, alloc_end)) = Self::region_from_node(size, align, node) {
-                let next = node.next.take();
-                let region = Region::new(current.next.take().unwrap(), alloc_start, alloc_end);
-                current.next = next;
-                return Some(region);
-            } else {
-                current = current.next.as_mut().unwrap();
-            }
+        if next.as_deref().map_or(false, |node| node.start_address() == start + size) {
+            let node = next.unwrap();
+            size += node.size;
+            next = node.next.take();
         }
 
-        if !merged {
-            self.merge_list();
-            return self.find_region(size, align, true);
+        if parent != head && (*parent).end_address() == start {
+            (*parent).size += size;
+            (*parent).next = next;
+        } else {
+            let ptr = start as *mut MemoryNode;
+            ptr.write(MemoryNode { size, next });
+            (*parent).next = Some(&mut *ptr);
+        }
+    }
+
+    pub unsafe fn find_region(&mut self, size: usize, align: usize) -> Option<Region> {
+        if let Some(region) = self.take_region(size, align) {
+            return Some(region);
+        }
+
+        // the new pages join the last free region, which is then large enough for any alignment
+        if self.grow(size + align + MEMORY_NODE_SIZE) {
+            return self.take_region(size, align);
         }
 
-        // TODO: expand heap if nothing is found
         return None;
     }
 
+    // removes the region that fits best according to the policy from the free list
+    unsafe fn take_region(&mut self, size: usize, align: usize) -> Option<Region> {
+        let mut found: Option<(*mut MemoryNode, usize, usize, usize)> = None;
+        let mut parent = &mut self.list as *mut MemoryNode;
+
+        while let Some(node) = (*parent).next.as_deref_mut() {
+            if let Ok((alloc_start, alloc_end)) = Self::region_from_node(size, align, node) {
+                if found.map_or(true, |(_, found_size, _, _)| node.size < found_size) {
+                    found = Some((parent, node.size, alloc_start, alloc_end));
+                }
+                if self.policy == FitPolicy::First {
+                    break;
+                }
+            }
+            parent = node;
+        }
+
+        let (parent, _, alloc_start, alloc_end) = found?;
+        let node = (*parent).next.take().unwrap();
+        (*parent).next = node.next.take();
+        Some(Region::new(node.start_address(), node.end_address(), alloc_start, alloc_end))
+    }
+
+    // The space left before and after the allocation goes back to the free list, so it has to be
+    // empty or large enough for a node.
     fn region_from_node(size: usize, align: usize, node: &MemoryNode) -> Result<(usize, usize), ()> {
-        let alloc_start = align_address(node.start_address(), align);
-        let alloc_end = align_address(alloc_start + size, MEMORY_NODE_ALIGN);
+        let mut alloc_start = VirtualAddress::new(node.start_address()).align_up(align).data();
+        if alloc_start != node.start_address() && alloc_start - node.start_address() < MEMORY_NODE_SIZE {
+            alloc_start = VirtualAddress::new(node.start_address() + MEMORY_NODE_SIZE).align_up(align).data();
+        }
+        let alloc_end = VirtualAddress::new(alloc_start + size).align_up(MEMORY_NODE_ALIGN).data();
 
         if node.end_address() < alloc_end {
             return Err(())
@@ -127,68 +416,13 @@ impl LinkedHeap {
     fn actual_size(layout: Layout) -> usize {
         layout.size().max(MEMORY_NODE_SIZE)
     }
+}
 
-    unsafe fn merge_list(&mut self) {
-        crate::logln!("[allocator] Merging allocator list.");
-        // TODO: fix this cheat
-        let head = &mut *(self.list.start_address() as *mut MemoryNode);
-        self.merge_list_rec(head);
-    }
-
-    unsafe fn merge_list_rec(&mut self, current: &mut MemoryNode) {
-        if current.next.is_none() {
-            return
-        }
-
-        let adjacent = self.find_adjacent_node_parent(current.next.as_ref().unwrap());
-
-        if let Some(adjacent_parent) = adjacent {
-            let merged = Self::merge_nodes(current, adjacent_parent);
-            self.free_region(merged.0, merged.1);
-            self.merge_list();
-        } else {
-            if let Some(node) = &mut current.next {
-                self.merge_list_rec(node)
-            }
-        }
-    }
-
-    fn find_adjacent_node_parent(&mut self, node: &MemoryNode) -> Option<&mut MemoryNode> {
-        let mut current = &mut self.list;
-
-        while let Some(candidate) = &current.next {
-            if candidate.start_address() >= node.end_address()
-                && candidate.start_address() - node.end_address() < MEMORY_NODE_SIZE
-            {
-                return Some(current)
-            }
-
-            if candidate.end_address() <= node.start_address()
-                && node.start_address() - candidate.end_address() < MEMORY_NODE_SIZE
-            {
-                return Some(current)
-            }
-
-            current = current.next.as_mut().unwrap();
-        }
-
-        return None;
-    }
-
-    fn merge_nodes(
-        node1_parent: &mut MemoryNode,
-        node2_parent: &mut MemoryNode,
-    ) -> (usize, usize) {
-        let node1 = node1_parent.next.take().unwrap();
-        let node2 = node2_parent.next.take().unwrap();
-
-        node1_parent.next = node1.next.take();
-        node2_parent.next = node2.next.take();
-
-        let start = node1.start_address().min(node2.start_address());
-        let end = node1.end_address().max(node2.end_address());
-
-        (start, end - start)
+// unmaps the heap pages from `start` up to `end` and frees their frames
+unsafe fn unmap_pages(start: Page<Size4KiB>, end: Page<Size4KiB>) {
+    for page in Page::range(start, end) {
+        let frame = crate::with_active_root!(|root| unmap(&page.start_address(), root));
+        FRAME_MAP.lock().free(frame);
     }
 }
 
@@ -197,16 +431,16 @@ unsafe impl GlobalAlloc for Locked<LinkedHeap> {
         let mut allocator = self.lock();
 
         let aligned_layout = LinkedHeap::aligned_layout(layout);
-        if let Some(region) = allocator.find_region(
-            aligned_layout.size().max(core::mem::size_of::<MemoryNode>()),
-            LinkedHeap::actual_size(layout),
-            false,
-        ) {
-            let remainder = region.node.end_address() - region.alloc_end;
-            if remainder > 0 {
-                let aligned = align_address(region.alloc_end, core::mem::align_of::<MemoryNode>());
-                allocator.free_region(aligned, region.node.end_address() - aligned);
+        if let Some(region) = allocator.find_region(LinkedHeap::actual_size(aligned_layout), aligned_layout.align()) {
+            if region.alloc_start > region.start {
+                allocator.free_region(region.start, region.alloc_start - region.start);
             }
+            if region.end > region.alloc_end {
+                allocator.free_region(region.alloc_end, region.end - region.alloc_end);
+            }
+            allocator.allocated += region.alloc_end - region.alloc_start;
+            allocator.peak = allocator.peak.max(allocator.allocated);
+            allocator.allocations += 1;
             return region.alloc_start as *mut u8;
         } else {
             return core::ptr::null_mut()
@@ -217,6 +451,40 @@ unsafe impl GlobalAlloc for Locked<LinkedHeap> {
         let size = LinkedHeap::actual_size(LinkedHeap::aligned_layout(layout));
         let mut allocator = self.lock();
         allocator.free_region(ptr as usize, size);
+        allocator.allocated -= size;
+        allocator.frees += 1;
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if HEAP_READY.load(Ordering::Acquire) {
+            let ptr = match slab::size_class(layout) {
+                Some(cache) => cache.lock().alloc(),
+                None => ALLOCATOR.alloc(layout),
+            };
+            if !ptr.is_null() {
+                let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+                PEAK.fetch_max(allocated, Ordering::Relaxed);
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            ptr
+        } else {
+            EARLY_ALLOCATOR.lock().alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if in_early_arena(ptr) {
+            EARLY_ALLOCATOR.lock().dealloc(ptr, layout);
+        } else {
+            match slab::size_class(layout) {
+                Some(cache) => cache.lock().dealloc(ptr),
+                None => ALLOCATOR.dealloc(ptr, layout),
+            }
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            FREES.fetch_add(1, Ordering::Relaxed);
+        }
     }
 }
 
@@ -252,3 +520,188 @@ fn mem_allocator_create_vector() {
     let boxed = Box::new(412);
     assert_eq!(*boxed.deref(), 412);
 }
+
+#[cfg(test)]
+const PAGE: usize = FrameSize::SMALL as usize;
+
+// A heap of its own for tests, somewhere in the kernel half nothing else uses. Its pages are
+// unmapped and their frames freed when it is dropped.
+#[cfg(test)]
+struct TestHeap(Locked<LinkedHeap>);
+
+#[cfg(test)]
+impl TestHeap {
+    fn new(size: usize, max_size: usize) -> TestHeap {
+        let heap = Locked::new(LinkedHeap::new());
+        unsafe { heap.lock().init(0xFFFF_D000_0000_0000, size, max_size) };
+        TestHeap(heap)
+    }
+}
+
+#[cfg(test)]
+impl Deref for TestHeap {
+    type Target = Locked<LinkedHeap>;
+
+    fn deref(&self) -> &Locked<LinkedHeap> {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestHeap {
+    fn drop(&mut self) {
+        let heap = self.0.lock();
+        let start = Page::containing_address(VirtualAddress::new(heap.start));
+        let end = Page::containing_address(VirtualAddress::new(heap.limit));
+        unsafe { unmap_pages(start, end) };
+    }
+}
+
+#[os_test]
+fn mem_allocator_grow_shrink() {
+    let heap = TestHeap::new(2 * PAGE, 8 * PAGE);
+
+    let layout = Layout::from_size_align(3 * PAGE, 8).unwrap();
+    let ptr = unsafe { heap.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(heap.lock().size() > 2 * PAGE);
+    // mapped up front, this would be a page fault otherwise
+    unsafe { ptr.write_bytes(0xAB, layout.size()) };
+
+    assert!(unsafe { heap.alloc(Layout::from_size_align(16 * PAGE, 8).unwrap()) }.is_null());
+
+    unsafe { heap.dealloc(ptr, layout) };
+    assert!(unsafe { heap.lock().shrink() } > 0);
+    assert_eq!(heap.lock().size(), 2 * PAGE);
+
+    // the initial pages stay
+    assert_eq!(unsafe { heap.lock().shrink() }, 0);
+}
+
+// Takes free frames until only `keep` are left. They are chained through their first word, so the
+// returned address is all `release_frames` needs.
+#[cfg(test)]
+fn hoard_frames(keep: usize) -> usize {
+    let mut frames = FRAME_MAP.lock();
+    let mut hoard = 0;
+    while frames.free_frames() > keep {
+        let frame = frames.alloc_free();
+        unsafe { *frame.start_address.as_mut_ptr::<usize>() = hoard };
+        hoard = frame.start_address.data();
+    }
+    hoard
+}
+
+#[cfg(test)]
+fn release_frames(mut hoard: usize) {
+    // frame 0 is never handed out, so it ends the chain
+    while hoard != 0 {
+        let address = crate::mem::address::PhysicalAddress::new(hoard);
+        hoard = unsafe { *address.as_ptr::<usize>() };
+        FRAME_MAP.lock().free(crate::mem::frames::Frame { start_address: address, free: false, size: FrameSize::SMALL });
+    }
+}
+
+#[os_test]
+fn mem_allocator_grow_out_of_frames() {
+    // fills a whole l1 table, so the next page needs a new one
+    let heap = TestHeap::new(512 * PAGE, 1024 * PAGE);
+    let full = Layout::from_size_align(512 * PAGE, 8).unwrap();
+    let ptr = unsafe { heap.alloc(full) };
+    assert!(!ptr.is_null());
+
+    // one frame for the page, none for the table
+    let hoard = hoard_frames(1);
+    assert!(unsafe { heap.alloc(Layout::from_size_align(PAGE, 8).unwrap()) }.is_null());
+    assert_eq!(FRAME_MAP.lock().free_frames(), 1);
+    assert_eq!(heap.lock().size(), 512 * PAGE);
+
+    // no frame for the page either
+    let last = hoard_frames(0);
+    assert!(unsafe { heap.alloc(Layout::from_size_align(PAGE, 8).unwrap()) }.is_null());
+    assert_eq!(heap.lock().size(), 512 * PAGE);
+
+    release_frames(last);
+    release_frames(hoard);
+    unsafe { heap.dealloc(ptr, full) };
+}
+
+#[os_test]
+fn mem_allocator_stress() {
+    const SLOTS: usize = 64;
+    let heap = TestHeap::new(4 * PAGE, 64 * PAGE);
+
+    // xorshift, the sequence only has to be the same on every run
+    let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+
+    let mut slots: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    for policy in [FitPolicy::First, FitPolicy::Best] {
+        heap.lock().set_policy(policy);
+        for _ in 0..4000 {
+            let slot = random() % SLOTS;
+            match slots[slot].take() {
+                Some((ptr, layout)) => unsafe {
+                    // a neighbour writing past its end would show up here
+                    assert!((0..layout.size()).all(|i| *ptr.add(i) == slot as u8));
+                    heap.dealloc(ptr, layout);
+                },
+                None => {
+                    let layout = Layout::from_size_align(1 + random() % 1024, 1 << (random() % 7)).unwrap();
+                    let ptr = unsafe { heap.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % layout.align(), 0);
+                    unsafe { ptr.write_bytes(slot as u8, layout.size()) };
+                    slots[slot] = Some((ptr, layout));
+                }
+            }
+        }
+    }
+    for (ptr, layout) in slots.iter_mut().filter_map(Option::take) {
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+
+    // with everything freed, the whole heap is a single region again
+    let heap = heap.lock();
+    let node = heap.list.next.as_deref().unwrap();
+    assert!(node.next.is_none());
+    assert_eq!((node.start_address(), node.size), (heap.start, heap.size()));
+}
+
+#[os_test]
+fn mem_allocator_stats() {
+    let heap = TestHeap::new(4 * PAGE, 4 * PAGE);
+
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let large = Layout::from_size_align(PAGE, 8).unwrap();
+    let first = unsafe { heap.alloc(small) };
+    let second = unsafe { heap.alloc(large) };
+    unsafe { heap.dealloc(first, small) };
+
+    let stats = heap.lock().stats();
+    assert_eq!((stats.allocations, stats.frees), (2, 1));
+    assert_eq!((stats.allocated, stats.peak), (PAGE, PAGE + 104));
+    // the hole left by the first allocation, and everything after the second
+    assert_eq!((stats.free_regions, stats.free_bytes), (2, 3 * PAGE));
+    assert_eq!(stats.largest_free, 3 * PAGE - 104);
+    assert_eq!(stats.fragmentation, 1);
+
+    unsafe { heap.dealloc(second, large) };
+    let stats = heap.lock().stats();
+    assert_eq!((stats.allocated, stats.free_regions, stats.largest_free), (0, 1, 4 * PAGE));
+
+    // the kernel allocator counts small allocations as well, not only the slabs they come from
+    let before = heap_stats();
+    let boxed = Box::new(0u64);
+    let during = heap_stats();
+    assert_eq!((during.allocated, during.allocations), (before.allocated + 8, before.allocations + 1));
+    assert!(during.peak >= during.allocated);
+    drop(boxed);
+    let after = heap_stats();
+    assert_eq!((after.allocated, after.frees), (before.allocated, before.frees + 1));
+}
@@ -0,0 +1,220 @@
+use macros::os_test;
+
+use crate::mem::address::PhysicalAddress;
+use crate::mem::frames::FrameSize;
+
+// a block of order n spans 2^n frames, so the largest one is a 1 GiB frame
+pub const MAX_ORDER: usize = 18;
+const FRAME_SIZE: usize = FrameSize::SMALL as usize;
+// for frames that don't start a free block
+const NOT_FREE: u8 = u8::MAX;
+
+// kept in the first frame of every free block, so the lists don't need any memory of their own
+struct FreeBlock {
+    next: Option<usize>,
+    previous: Option<usize>,
+}
+
+// Hands out blocks of 2^order frames. Larger blocks are split in halves (buddies) when needed, and
+// a freed block is merged with its buddy as long as that one is free as well.
+pub struct BuddyAllocator {
+    base: PhysicalAddress,
+    // order of the free block starting at each frame
+    orders: &'static mut [u8],
+    free_lists: [Option<usize>; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyAllocator {
+    // manages `orders.len()` frames from `base`, there is nothing to allocate until `add_free`
+    pub fn new(base: PhysicalAddress, orders: &'static mut [u8]) -> BuddyAllocator {
+        orders.fill(NOT_FREE);
+        BuddyAllocator { base, orders, free_lists: [None; MAX_ORDER + 1], free_frames: 0 }
+    }
+
+    // smallest order with at least `count` frames
+    pub fn order(count: usize) -> usize {
+        count.next_power_of_two().trailing_zeros() as usize
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // adds free frames as the largest blocks their alignment allows
+    pub fn add_free(&mut self, start: PhysicalAddress, count: usize) {
+        let mut index = (start - self.base) / FRAME_SIZE;
+        let end = index + count;
+        assert!(end <= self.orders.len(), "Frames up to 0x{:X} are not managed by this allocator.", start.data());
+        while index < end {
+            let fits = (usize::BITS - 1 - (end - index).leading_zeros()) as usize;
+            let order = (index.trailing_zeros() as usize).min(fits).min(MAX_ORDER);
+            self.push(index, order);
+            index += 1 << order;
+        }
+    }
+
+    pub fn alloc(&mut self, order: usize) -> Option<PhysicalAddress> {
+        self.take(order, self.orders.len())
+    }
+
+    // Like `alloc`, but the block has to end at or below `max_address`. Has to walk the lists to
+    // find one, so it's only meant for the occasional DMA buffer.
+    pub fn alloc_below(&mut self, order: usize, max_address: PhysicalAddress) -> Option<PhysicalAddress> {
+        let limit = max_address.data().saturating_sub(self.base.data()) / FRAME_SIZE;
+        self.take(order, limit.min(self.orders.len()))
+    }
+
+    pub fn free(&mut self, address: PhysicalAddress, order: usize) {
+        let mut index = self.index(address);
+        let mut order = order;
+        assert!(index % (1 << order) == 0, "Block at 0x{:X} is not aligned to its order.", address.data());
+        assert!(self.orders[index] == NOT_FREE, "Double free of block at 0x{:X}.", address.data());
+
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.orders.len() || self.orders[buddy] != order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    // smallest block that fits below `limit`, split down to `order`
+    fn take(&mut self, order: usize, limit: usize) -> Option<PhysicalAddress> {
+        let (index, mut block_order) = (order..=MAX_ORDER).find_map(|block_order| {
+            let mut current = self.free_lists[block_order];
+            while let Some(index) = current {
+                if index + (1 << order) <= limit {
+                    return Some((index, block_order));
+                }
+                current = unsafe { (*self.block(index)).next };
+            }
+            None
+        })?;
+
+        self.remove(index, block_order);
+        // the upper halves go back to the lists
+        while block_order > order {
+            block_order -= 1;
+            self.push(index + (1 << block_order), block_order);
+        }
+        Some(self.base + index * FRAME_SIZE)
+    }
+
+    fn index(&self, address: PhysicalAddress) -> usize {
+        let index = (address - self.base) / FRAME_SIZE;
+        assert!(index < self.orders.len(), "Frame 0x{:X} is not managed by this allocator.", address.data());
+        index
+    }
+
+    fn block(&self, index: usize) -> *mut FreeBlock {
+        (self.base + index * FRAME_SIZE).as_mut_ptr::<FreeBlock>()
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let next = self.free_lists[order];
+        unsafe {
+            *self.block(index) = FreeBlock { next, previous: None };
+            if let Some(next) = next {
+                (*self.block(next)).previous = Some(index);
+            }
+        }
+        self.free_lists[order] = Some(index);
+        self.orders[index] = order as u8;
+        self.free_frames += 1 << order;
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let FreeBlock { next, previous } = unsafe { self.block(index).read() };
+        match previous {
+            Some(previous) => unsafe { (*self.block(previous)).next = next },
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            unsafe { (*self.block(next)).previous = previous };
+        }
+        self.orders[index] = NOT_FREE;
+        self.free_frames -= 1 << order;
+    }
+}
+
+// 2 MiB of real memory, so the blocks can be written to
+#[cfg(test)]
+fn test_allocator(orders: &'static mut [u8; 512]) -> BuddyAllocator {
+    use crate::mem::frames::FRAME_MAP;
+
+    let limit = PhysicalAddress::new(4 * crate::mem::GiB);
+    let frames = FRAME_MAP.lock().alloc_contiguous(512, FrameSize::LARGE as usize, limit).unwrap();
+    BuddyAllocator::new(frames.start.start_address(), orders)
+}
+
+#[cfg(test)]
+fn return_test_allocator(buddy: BuddyAllocator) {
+    use crate::mem::frames::{Frame, FRAME_MAP};
+
+    let frame = Frame { start_address: buddy.base, free: false, size: FrameSize::LARGE };
+    FRAME_MAP.lock().free(frame);
+}
+
+#[os_test]
+fn mem_buddy_split_merge() {
+    static mut ORDERS: [u8; 512] = [0; 512];
+    let mut buddy = test_allocator(unsafe { &mut *core::ptr::addr_of_mut!(ORDERS) });
+    let base = buddy.base;
+    buddy.add_free(base, 512);
+    assert_eq!(buddy.free_lists[9], Some(0));
+
+    // a single frame splits the block all the way down, leaving one free block per order
+    let first = buddy.alloc(0).unwrap();
+    assert_eq!(first, base);
+    for order in 0..9 {
+        assert_eq!(buddy.free_lists[order], Some(1 << order));
+    }
+    assert_eq!(buddy.free_lists[9], None);
+
+    let second = buddy.alloc(0).unwrap();
+    assert_eq!(second, base + FRAME_SIZE);
+    assert_eq!(buddy.free_frames(), 510);
+
+    // the first frame can't merge while its buddy is used, the second one merges everything again
+    buddy.free(first, 0);
+    assert_eq!(buddy.free_lists[0], Some(0));
+    buddy.free(second, 0);
+    assert_eq!(buddy.free_lists[0], None);
+    assert_eq!(buddy.free_lists[9], Some(0));
+    assert_eq!(buddy.free_frames(), 512);
+
+    return_test_allocator(buddy);
+}
+
+#[os_test]
+fn mem_buddy_alloc_below() {
+    static mut ORDERS: [u8; 512] = [0; 512];
+    let mut buddy = test_allocator(unsafe { &mut *core::ptr::addr_of_mut!(ORDERS) });
+    let base = buddy.base;
+    buddy.add_free(base, 16);
+    buddy.add_free(base + 256 * FRAME_SIZE, 256);
+    assert_eq!(buddy.free_lists[4], Some(0));
+    assert_eq!(buddy.free_lists[8], Some(256));
+
+    // too large for the low block
+    let high = buddy.alloc(BuddyAllocator::order(32)).unwrap();
+    assert_eq!(high, base + 256 * FRAME_SIZE);
+    assert!(buddy.alloc_below(5, base + 128 * FRAME_SIZE).is_none());
+
+    let low = buddy.alloc_below(2, base + 4 * FRAME_SIZE).unwrap();
+    assert_eq!(low, base);
+
+    // the two free regions aren't buddies, so they never merge
+    buddy.free(high, 5);
+    buddy.free(low, 2);
+    assert_eq!(buddy.free_lists[4], Some(0));
+    assert_eq!(buddy.free_lists[8], Some(256));
+    assert_eq!(buddy.free_frames(), 272);
+
+    return_test_allocator(buddy);
+}
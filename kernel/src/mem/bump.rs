@@ -0,0 +1,109 @@
+use core::alloc::Layout;
+use macros::os_test;
+
+use crate::mem::address::VirtualAddress;
+use crate::mem::KiB;
+use crate::util::locked::Locked;
+
+const EARLY_ARENA_SIZE: usize = 128 * KiB;
+
+#[repr(C, align(4096))]
+struct Arena([u8; EARLY_ARENA_SIZE]);
+
+// part of .bss, so it is mapped before anything else is set up
+static mut EARLY_ARENA: Arena = Arena([0; EARLY_ARENA_SIZE]);
+
+// serves allocations until the heap exists, see `allocator::use_heap`
+pub static EARLY_ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+
+// Whether `ptr` is in the arena of `EARLY_ALLOCATOR`, without taking its lock. The arena never moves,
+// so this is the same as asking the allocator.
+pub fn in_early_arena(ptr: *mut u8) -> bool {
+    let start = core::ptr::addr_of!(EARLY_ARENA) as usize;
+    (ptr as usize) >= start && (ptr as usize) < start + EARLY_ARENA_SIZE
+}
+
+// Hands out memory by moving a pointer forward. Freed memory is only reused once everything has
+// been freed, or if it was the most recent allocation.
+pub struct BumpAllocator {
+    start: usize,
+    end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> BumpAllocator {
+        BumpAllocator { start: 0, end: 0, next: 0, allocations: 0 }
+    }
+
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        self.start = start;
+        self.end = start + size;
+        self.next = start;
+        self.allocations = 0;
+    }
+
+    pub unsafe fn init_arena(&mut self) {
+        self.init(core::ptr::addr_of_mut!(EARLY_ARENA) as usize, EARLY_ARENA_SIZE);
+    }
+
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        (ptr as usize) >= self.start && (ptr as usize) < self.end
+    }
+
+    pub fn used(&self) -> usize {
+        self.next - self.start
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if self.start == 0 {
+            return core::ptr::null_mut();
+        }
+        let start = VirtualAddress::new(self.next).align_up(layout.align()).data();
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.end => {
+                self.next = end;
+                self.allocations += 1;
+                start as *mut u8
+            }
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.start;
+        } else if ptr as usize + layout.size() == self.next {
+            self.next = ptr as usize;
+        }
+    }
+}
+
+#[os_test]
+fn mem_bump_alloc() {
+    static mut ARENA: [u64; 64] = [0; 64];
+    let start = core::ptr::addr_of_mut!(ARENA) as usize;
+    let mut bump = BumpAllocator::new();
+    unsafe { bump.init(start, 512) };
+
+    let byte = Layout::new::<u8>();
+    let words = Layout::from_size_align(64, 64).unwrap();
+    let first = bump.alloc(byte);
+    let second = bump.alloc(words);
+    assert_eq!(first as usize, start);
+    assert_eq!(second as usize % 64, 0);
+    assert!(bump.contains(first) && !in_early_arena(first));
+
+    // the latest allocation can be rolled back, the one before has to wait for everything else
+    bump.dealloc(second, words);
+    assert_eq!(bump.used(), second as usize - start);
+    assert!(bump.alloc(Layout::from_size_align(1024, 8).unwrap()).is_null());
+    bump.dealloc(first, byte);
+    assert_eq!(bump.used(), 0);
+}
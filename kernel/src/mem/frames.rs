@@ -3,23 +3,42 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use macros::os_test;
 
-use crate::BootData;
-use crate::multiboot::{MemoryKind, MemoryMapPointer};
+use crate::boot::info::{BootInfo, MemoryKind, MemoryRegion, Reservation};
 use crate::mem::address::PhysicalAddress;
-use crate::mem::KiB;
+use crate::mem::buddy::BuddyAllocator;
+use crate::mem::page::{PhysFrame, PhysFrameRange};
+use crate::mem::{GiB, KiB};
 
 const FRAME_SIZE: usize = 4096;
+const WORD_BITS: usize = u64::BITS as usize;
 
 lazy_static! {
-    pub static ref FRAME_MAP: Mutex<FrameMap> = Mutex::new(FrameMap { total_frames: 0, frames: &mut [] });
+    pub static ref FRAME_MAP: Mutex<FrameMap> = Mutex::new(FrameMap::new());
+}
+
+// which allocator hands out frames, chosen with the `frames` boot option
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameAllocator {
+    Bitmap,
+    Buddy,
+}
+
+impl FrameAllocator {
+    pub fn parse(value: &str) -> Option<FrameAllocator> {
+        match value {
+            "bitmap" => Some(FrameAllocator::Bitmap),
+            "buddy" => Some(FrameAllocator::Buddy),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Copy)]
 #[repr(usize)]
 pub enum FrameSize {
     SMALL = FRAME_SIZE,
-    LARGE = FRAME_SIZE * FRAME_SIZE,
-    HUGE = FRAME_SIZE * FRAME_SIZE * FRAME_SIZE,
+    LARGE = FRAME_SIZE * 512, // 2 MiB
+    HUGE = FRAME_SIZE * 512 * 512, // 1 GiB
 }
 
 pub struct Frame {
@@ -31,11 +50,9 @@ pub struct Frame {
 impl Frame {
     pub fn for_address(address: PhysicalAddress) -> Frame {
         let frame = address.data() >> 12; // shift away address offset
-        let byte = frame / 8;
-        let offset = frame % 8;
         Frame {
             start_address: PhysicalAddress::new(frame << 12),
-            free: FRAME_MAP.lock().frames[byte as usize] & (0x1 << offset) == 0,
+            free: !FRAME_MAP.lock().is_used(frame),
             size: FrameSize::SMALL,
         }
     }
@@ -43,111 +60,296 @@ impl Frame {
 
 pub struct FrameMap {
     total_frames: usize,
-    frames: &'static mut [u8],
+    free_frames: usize,
+    // word the last frame was allocated from, the next search starts there
+    next_word: usize,
+    frames: &'static mut [u64],
+    // owners besides the first one, so 0 for frames that aren't shared
+    shared: &'static mut [u16],
+    // memory for the buddy allocator, which is only set up on request
+    orders: &'static mut [u8],
+    buddy: Option<BuddyAllocator>,
 }
 
 // TODO: optimize setting blocks
 impl FrameMap {
+    pub const fn new() -> FrameMap {
+        FrameMap {
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+            frames: &mut [],
+            shared: &mut [],
+            orders: &mut [],
+            buddy: None,
+        }
+    }
+
     // TODO: setup paging as needed for frame map
-    pub unsafe fn init(&mut self, boot_data: &BootData) {
-        let start_address = PhysicalAddress::new((boot_data.kernel_end / FRAME_SIZE + 1) * FRAME_SIZE);
+    pub unsafe fn init(&mut self, boot_info: &BootInfo) {
+        let total_frames = boot_info.memory_end().div_ceil(FRAME_SIZE);
+        let size = FrameMap::buffer_size(total_frames);
+        let start_address = FrameMap::find_buffer(boot_info.memory_regions(), boot_info.reservations(), size)
+            .expect("No room for the frame map below 1 GiB, the only memory mapped this early.");
 
         crate::logln!("[frames] Creating frame map starting at 0x{:X}.", start_address.data());
 
-        self.create_buffer(start_address, boot_data.mb_info.memory_map());
-
-        let mut current = Some(boot_data.mb_info.memory_map());
-        loop {
-            match current {
-                Some(pointer) => {
-                    if { pointer.entry.kind } == MemoryKind::Usable {
-                        let mut first = pointer.entry.base as usize / FRAME_SIZE;
-                        if pointer.entry.base as usize % FRAME_SIZE != 0 {
-                            first += 1
-                        }
-                        let mut count = pointer.entry.limit as usize / FRAME_SIZE;
-                        if pointer.entry.limit as usize % FRAME_SIZE != 0 {
-                            count += 1;
-                        }
-
-                        for i in 0..count.max(self.frames.len()) {
-                            self.set_frame(i + first, true);
-                        }
-                    }
-                    current = pointer.next();
-                }
-                None => break
-            }
-        }
-
-        // mark everything until end of frame map as used
-        let frames_used = self.frames.len() / FRAME_SIZE + 1;
-        let frame_index = (self as *const FrameMap as usize) / FRAME_SIZE;
-        let last_frame = frame_index + frames_used + 1;
-        for i in 0..last_frame {
-            self.set_frame(i, false);
-        }
+        let buffer = Reservation { start: start_address.data(), end: start_address.data() + size, name: "frame map" };
+        self.setup(
+            start_address,
+            total_frames,
+            boot_info.memory_regions(),
+            boot_info.reservations().chain(core::iter::once(buffer)),
+        );
 
         crate::logln!(
-            "[frames] Created frame map for {} frames ({} KiBs).",
+            "[frames] Created frame map for {} frames ({} KiBs), {} are free.",
             self.total_frames,
             self.total_frames * FRAME_SIZE / KiB,
+            self.free_frames,
         );
     }
 
-    unsafe fn create_buffer(&mut self, start_address: PhysicalAddress, memory_map: MemoryMapPointer) {
-        let mut last_address: usize = 0;
+    // Frees whole frames inside usable regions, then takes the reserved ones back. A frame that is
+    // only partly usable or partly reserved is never handed out.
+    unsafe fn setup(
+        &mut self,
+        start_address: PhysicalAddress,
+        total_frames: usize,
+        memory_regions: &[MemoryRegion],
+        reservations: impl Iterator<Item = Reservation>,
+    ) {
+        self.create_buffer(start_address, total_frames);
 
-        let mut current = Some(memory_map);
-        loop {
-            match current {
-                Some(pointer) => {
-                    if { pointer.entry.kind } == MemoryKind::Usable {
-                        last_address = (pointer.entry.base + pointer.entry.limit) as usize
-                    }
-                    current = pointer.next();
+        for region in memory_regions.iter().filter(|region| region.kind == MemoryKind::Usable) {
+            let first = region.base.div_ceil(FRAME_SIZE);
+            let last = ((region.base + region.length) / FRAME_SIZE).min(self.total_frames);
+            for i in first..last {
+                self.set_frame(i, true);
+            }
+        }
+
+        for reservation in reservations {
+            let first = reservation.start / FRAME_SIZE;
+            let last = reservation.end.div_ceil(FRAME_SIZE).min(self.total_frames);
+            for i in first..last {
+                self.set_frame(i, false);
+            }
+            crate::debugln!(
+                "[frames] Reserved 0x{:X} - 0x{:X} ({}).",
+                reservation.start,
+                reservation.end,
+                reservation.name,
+            );
+        }
+    }
+
+    // Lowest usable spot that doesn't overlap a reservation. Only the first GiB is mapped this early.
+    fn find_buffer(
+        memory_regions: &[MemoryRegion],
+        reservations: impl Iterator<Item = Reservation> + Clone,
+        size: usize,
+    ) -> Option<PhysicalAddress> {
+        for region in memory_regions.iter().filter(|region| region.kind == MemoryKind::Usable) {
+            let end = (region.base + region.length).min(GiB);
+            let mut start = PhysicalAddress::new(region.base).align_up(FRAME_SIZE);
+            while start.data() + size <= end {
+                let overlap = reservations
+                    .clone()
+                    .find(|reservation| reservation.start < start.data() + size && reservation.end > start.data());
+                match overlap {
+                    Some(reservation) => start = PhysicalAddress::new(reservation.end).align_up(FRAME_SIZE),
+                    None => return Some(start),
                 }
-                None => break
             }
         }
+        None
+    }
 
-        self.total_frames = last_address / FRAME_SIZE + usize::from(last_address % FRAME_SIZE != 0);
+    // bitmap, reference counts and buddy orders, see `create_buffer`
+    fn buffer_size(total_frames: usize) -> usize {
+        let words = total_frames.div_ceil(WORD_BITS);
+        words * core::mem::size_of::<u64>() + total_frames * (core::mem::size_of::<u16>() + core::mem::size_of::<u8>())
+    }
+
+    unsafe fn create_buffer(&mut self, start_address: PhysicalAddress, total_frames: usize) {
+        self.total_frames = total_frames;
         self.frames = from_raw_parts_mut(
-            start_address.data() as *mut u8,
-            self.total_frames / 8 + usize::from(self.total_frames % FRAME_SIZE != 0),
+            start_address.as_mut_ptr::<u64>(),
+            self.total_frames.div_ceil(WORD_BITS),
         );
 
-        self.frames.fill(u8::MAX);
+        self.frames.fill(u64::MAX);
+        self.free_frames = 0;
+        self.next_word = 0;
+
+        // reference counts follow right after the bitmap
+        let shared_address = start_address + self.frames.len() * core::mem::size_of::<u64>();
+        self.shared = from_raw_parts_mut(shared_address.as_mut_ptr::<u16>(), self.total_frames);
+        self.shared.fill(0);
+
+        let orders_address = shared_address + self.shared.len() * core::mem::size_of::<u16>();
+        self.orders = from_raw_parts_mut(orders_address.as_mut_ptr::<u8>(), self.total_frames);
+    }
+
+    // Hands all free frames to a buddy allocator, which serves every allocation from then on. The
+    // bitmap is still kept up to date, it knows which frames are used. Free blocks are written to,
+    // so this has to wait for the whole physical memory window.
+    pub unsafe fn enable_buddy(&mut self) {
+        let mut buddy = BuddyAllocator::new(PhysicalAddress::new(0), core::mem::take(&mut self.orders));
+
+        let mut index = 0;
+        while index < self.total_frames {
+            let start = index;
+            while index < self.total_frames && !self.is_used(index) {
+                index += 1;
+            }
+            if index > start {
+                buddy.add_free(PhysicalAddress::new(start * FRAME_SIZE), index - start);
+            }
+            index += 1;
+        }
+
+        crate::logln!("[frames] Buddy allocator has {} free frames.", buddy.free_frames());
+        self.buddy = Some(buddy);
     }
 
     fn set_frame(&mut self, index: usize, free: bool) {
-        assert!(index <= self.total_frames, "Frame outside expected range.");
-        let byte = index / 8;
-        let offset = index % 8;
+        assert!(index < self.total_frames, "Frame outside expected range.");
+        if self.is_used(index) != free {
+            return;
+        }
+        let word = index / WORD_BITS;
+        let bit = 0x1 << index % WORD_BITS;
         if free {
-            self.frames[byte] = self.frames[byte] & ((0x1 << offset) ^ 0xFF)
+            self.frames[word] &= !bit;
+            self.free_frames += 1;
         } else {
-            self.frames[byte] = self.frames[byte] | (0x1 << offset)
+            self.frames[word] |= bit;
+            self.free_frames -= 1;
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.frames[index / WORD_BITS] & (0x1 << index % WORD_BITS) != 0
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // Hands a frame back, large and huge frames free all the frames they span. Shared frames only
+    // drop a reference, they are released by their last owner.
+    pub fn free(&mut self, frame: Frame) {
+        let first = frame.start_address.data() / FRAME_SIZE;
+        let count = frame.size as usize / FRAME_SIZE;
+        for i in first..first + count {
+            if !self.is_used(i) {
+                panic!("Double free of frame 0x{:X}.", i * FRAME_SIZE);
+            }
+        }
+
+        if self.shared[first] > 0 {
+            self.shared[first] -= 1;
+            return;
+        }
+
+        for i in first..first + count {
+            self.set_frame(i, true);
+        }
+        if let Some(buddy) = &mut self.buddy {
+            buddy.free(frame.start_address, BuddyAllocator::order(count));
         }
     }
 
-    // TODO: make this something resembling performant
-    // TODO: allow collecting multiple frames
+    // adds an owner to an allocated frame, every owner has to free it
+    pub fn share(&mut self, frame: &Frame) {
+        let index = frame.start_address.data() / FRAME_SIZE;
+        assert!(self.is_used(index), "Can't share free frame 0x{:X}.", frame.start_address.data());
+        self.shared[index] = self.shared[index].checked_add(1).expect("Too many references to frame.");
+    }
+
+    pub fn references(&self, frame: &Frame) -> usize {
+        let index = frame.start_address.data() / FRAME_SIZE;
+        if self.is_used(index) {
+            self.shared[index] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    // Next fit: continues in the word of the previous allocation and wraps around at the end, so
+    // the full words in front of it don't have to be scanned again.
     pub fn alloc_free(&mut self) -> Frame {
-        let mut index = 0;
-        while self.frames[index] == u8::MAX {
-            index += 1
+        self.try_alloc_free().expect("Out of physical memory.")
+    }
+
+    // like `alloc_free`, for callers that can deal with running out of memory
+    pub fn try_alloc_free(&mut self) -> Option<Frame> {
+        if self.free_frames == 0 {
+            return None;
         }
-        let frame = index * 8 + self.frames[index].trailing_ones() as usize;
+        let frame = match &mut self.buddy {
+            Some(buddy) => buddy.alloc(0).unwrap().data() / FRAME_SIZE,
+            None => {
+                let words = self.frames.len();
+                let word = (0..words)
+                    .map(|offset| (self.next_word + offset) % words)
+                    .find(|&word| self.frames[word] != u64::MAX)
+                    .unwrap();
+                self.next_word = word;
+                word * WORD_BITS + self.frames[word].trailing_ones() as usize
+            }
+        };
         self.set_frame(frame, false);
 
-        crate::logln!("[frames] Allocated frame {} at address 0x{:X}.", frame, frame << 12);
-
-        Frame {
+        Some(Frame {
             start_address: PhysicalAddress::new(frame << 12),
             free: false,
             size: FrameSize::SMALL,
+        })
+    }
+
+    // Physically contiguous run of `count` frames, starting at a multiple of `align` and ending at or
+    // below `max_address`. Meant for DMA buffers and for building large frames. The bitmap looks for
+    // it first fit from the bottom and doesn't move the next fit hint.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize, max_address: PhysicalAddress) -> Option<PhysFrameRange> {
+        assert!(count > 0, "Can't allocate an empty run of frames.");
+        let step = align.max(FRAME_SIZE) / FRAME_SIZE;
+        assert!(step.is_power_of_two(), "Alignment has to be a power of two.");
+        let first = match &mut self.buddy {
+            Some(buddy) => {
+                let order = BuddyAllocator::order(count.max(step));
+                let address = buddy.alloc_below(order, max_address)?;
+                // whatever the run doesn't need goes straight back
+                buddy.add_free(address + count * FRAME_SIZE, (1 << order) - count);
+                address.data() / FRAME_SIZE
+            }
+            None => self.find_run(count, step, max_address)?,
+        };
+
+        for index in first..first + count {
+            self.set_frame(index, false);
         }
+        let start = PhysFrame::containing_address(PhysicalAddress::new(first * FRAME_SIZE));
+        Some(PhysFrame::range(start, start + count))
+    }
+
+    fn find_run(&self, count: usize, step: usize, max_address: PhysicalAddress) -> Option<usize> {
+        let limit = self.total_frames.min(max_address.data() / FRAME_SIZE);
+        let mut first = 0;
+        while first + count <= limit {
+            match (first..first + count).rev().find(|&index| self.is_used(index)) {
+                // the run can't contain the used frame, so continue behind it
+                Some(used) => first = (used / step + 1) * step,
+                None => return Some(first),
+            }
+        }
+        None
     }
 }
 
@@ -167,17 +369,134 @@ fn mem_frames_set_index() {
 
 #[os_test]
 fn mem_frames_alloc_free() {
-    let mut index = 0;
+    let free_frames = FRAME_MAP.lock().free_frames();
+
+    let alloc_frame = FRAME_MAP.lock().alloc_free();
+    assert_eq!(FRAME_MAP.lock().free_frames(), free_frames - 1);
+    assert!(!Frame::for_address(alloc_frame.start_address).free);
+
+    FRAME_MAP.lock().free(alloc_frame);
+    assert_eq!(FRAME_MAP.lock().free_frames(), free_frames);
+}
+
+#[os_test]
+fn mem_frames_shared_free() {
+    let frame = FRAME_MAP.lock().alloc_free();
+    let address = frame.start_address;
+    FRAME_MAP.lock().share(&frame);
+    assert_eq!(FRAME_MAP.lock().references(&frame), 2);
+
+    // the first owner only drops its reference
+    FRAME_MAP.lock().free(frame);
+    assert!(!Frame::for_address(address).free);
 
-    while !(Frame::for_address(PhysicalAddress::new(index << 12)).free) {
-        index += 1;
+    let frame = Frame { start_address: address, free: false, size: FrameSize::SMALL };
+    assert_eq!(FRAME_MAP.lock().references(&frame), 1);
+    FRAME_MAP.lock().free(frame);
+    assert!(Frame::for_address(address).free);
+}
+
+#[os_test]
+fn mem_frames_alloc_contiguous() {
+    let limit = PhysicalAddress::new(4 * crate::mem::GiB);
+    let frames = FRAME_MAP.lock().alloc_contiguous(16, 64 * KiB, limit).unwrap();
+    assert!(frames.start.start_address().is_aligned(64 * KiB));
+    assert!(frames.end.start_address() <= limit);
+    for frame in frames {
+        assert!(!Frame::for_address(frame.start_address()).free);
+        FRAME_MAP.lock().free(Frame::from(frame));
     }
 
-    let alloc_frame = FRAME_MAP.lock().alloc_free();
-    assert_eq!(
-        alloc_frame.start_address.data(),
-        index << 12,
-    );
+    // a run of 512 aligned frames is as good as a large frame
+    let frames = FRAME_MAP.lock().alloc_contiguous(512, FrameSize::LARGE as usize, limit).unwrap();
+    let large = Frame { start_address: frames.start.start_address(), free: false, size: FrameSize::LARGE };
+    assert!(large.start_address.is_aligned(FrameSize::LARGE as usize));
+    FRAME_MAP.lock().free(large);
+    assert!(Frame::for_address(frames.start.start_address()).free);
 
-    assert!(!Frame::for_address(alloc_frame.start_address).free)
+    assert!(FRAME_MAP.lock().alloc_contiguous(1, FRAME_SIZE, PhysicalAddress::new(0)).is_none());
+}
+
+// Allocation cost must not depend on how full the map is: the scan starts at the word of the last
+// allocation and skips full words, instead of walking every frame from the bottom.
+#[os_test]
+fn mem_frames_next_fit() {
+    const WORDS: usize = 16;
+    let total_frames = WORDS * WORD_BITS;
+    let count = FrameMap::buffer_size(total_frames).div_ceil(FRAME_SIZE);
+    let buffer = FRAME_MAP.lock().alloc_contiguous(count, FRAME_SIZE, PhysicalAddress::new(GiB)).unwrap();
+    let regions = [MemoryRegion { base: 0, length: total_frames * FRAME_SIZE, kind: MemoryKind::Usable }];
+    let mut map = FrameMap::new();
+    unsafe { map.setup(buffer.start.start_address(), total_frames, &regions, core::iter::empty()) };
+
+    // nearly full, only the first frame of word 1 and the last frame are left
+    for index in 0..total_frames {
+        map.set_frame(index, false);
+    }
+    map.set_frame(WORD_BITS, true);
+    map.set_frame(total_frames - 1, true);
+    map.next_word = WORDS - 1;
+
+    // the hint wins over the lower free frame, then the scan wraps around
+    assert_eq!(map.alloc_free().start_address.data(), (total_frames - 1) * FRAME_SIZE);
+    assert_eq!(map.alloc_free().start_address.data(), WORD_BITS * FRAME_SIZE);
+    assert_eq!(map.next_word, 1);
+    assert!(map.try_alloc_free().is_none());
+
+    // frames freed in front of the hint are found once it wraps around again
+    map.free(Frame { start_address: PhysicalAddress::new(3 * FRAME_SIZE), free: false, size: FrameSize::SMALL });
+    assert_eq!(map.alloc_free().start_address.data(), 3 * FRAME_SIZE);
+    assert_eq!(map.next_word, 0);
+
+    for frame in buffer {
+        FRAME_MAP.lock().free(Frame::from(frame));
+    }
+}
+
+#[os_test]
+fn mem_frames_reservations() {
+    fn usable(base: usize, length: usize) -> MemoryRegion {
+        MemoryRegion { base, length, kind: MemoryKind::Usable }
+    }
+
+    // what a BIOS machine with a hole at 8 MiB could look like, the last region isn't aligned
+    let regions = [
+        usable(0, 0x9_FC00),
+        MemoryRegion { base: 0x9_FC00, length: 0x400, kind: MemoryKind::Reserved },
+        usable(0x10_0000, 0x70_0000),
+        usable(0x90_0800, 0x10_0000),
+    ];
+    let reservations = [
+        Reservation { start: 0, end: 0x10_0000, name: "low memory" },
+        Reservation { start: 0x10_0000, end: 0x18_0000, name: "kernel" },
+        Reservation { start: 0x20_0000, end: 0x20_1800, name: "module" },
+        Reservation { start: 0x7F_F000, end: 0x7F_F010, name: "boot info" },
+    ];
+
+    let find = |size| FrameMap::find_buffer(&regions, reservations.iter().copied(), size).map(|address| address.data());
+    assert_eq!(find(0x3000), Some(0x18_0000));
+    assert_eq!(find(0x9_0000), Some(0x20_2000));
+    assert_eq!(find(0x80_0000), None);
+
+    // the map itself lives in real memory, the synthetic one only describes it
+    // up to the end of the highest usable region
+    let total_frames = 0xA0_0800usize.div_ceil(FRAME_SIZE);
+    let count = FrameMap::buffer_size(total_frames).div_ceil(FRAME_SIZE);
+    let buffer = FRAME_MAP.lock().alloc_contiguous(count, FRAME_SIZE, PhysicalAddress::new(GiB)).unwrap();
+    let mut map = FrameMap::new();
+    unsafe { map.setup(buffer.start.start_address(), total_frames, &regions, reservations.iter().copied()) };
+
+    let free_ranges = [(0x18_0000, 0x20_0000), (0x20_2000, 0x7F_F000), (0x90_1000, 0xA0_0000)];
+    let expected = free_ranges.iter().map(|(start, end)| (end - start) / FRAME_SIZE).sum::<usize>();
+    assert_eq!(map.free_frames(), expected);
+
+    // hands out every free frame, none of them may be reserved
+    while map.free_frames() > 0 {
+        let address = map.alloc_free().start_address.data();
+        assert!(free_ranges.iter().any(|&(start, end)| address >= start && address < end));
+    }
+
+    for frame in buffer {
+        FRAME_MAP.lock().free(Frame::from(frame));
+    }
 }
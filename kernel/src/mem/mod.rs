@@ -1,17 +1,18 @@
 pub(crate) mod frames;
+pub(crate) mod buddy;
 pub(crate) mod paging;
 pub(crate) mod address;
+pub(crate) mod page;
 pub(crate) mod allocator;
+pub(crate) mod bump;
+pub(crate) mod slab;
 
 pub(crate) static KiB: usize = 1024;
 pub(crate) static MiB: usize = KiB * KiB;
 pub(crate) static GiB: usize = KiB * KiB * KiB;
 
-pub(crate) fn align_address(address: usize, align: usize) -> usize {
-    let offset = address % align;
-    if offset == 0 {
-        address
-    } else {
-        address + (align - offset)
-    }
-}
+// The kernel is linked at this address and boot.s maps the first GiB of physical memory here.
+pub(crate) const KERNEL_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
+
+// All physical memory is mapped linearly from here, see `paging::kernel::map_physical_memory`.
+pub(crate) const PHYSICAL_OFFSET: usize = 0xFFFF_8000_0000_0000;
@@ -0,0 +1,158 @@
+use core::marker::PhantomData;
+use core::ops::Add;
+use macros::os_test;
+
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
+use crate::mem::frames::{Frame, FrameSize};
+
+pub trait PageSize: Copy + Eq + Ord {
+    const SIZE: usize;
+    const FRAME_SIZE: FrameSize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size4KiB {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size2MiB {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size1GiB {}
+
+impl PageSize for Size4KiB {
+    const SIZE: usize = FrameSize::SMALL as usize;
+    const FRAME_SIZE: FrameSize = FrameSize::SMALL;
+}
+
+impl PageSize for Size2MiB {
+    const SIZE: usize = FrameSize::LARGE as usize;
+    const FRAME_SIZE: FrameSize = FrameSize::LARGE;
+}
+
+impl PageSize for Size1GiB {
+    const SIZE: usize = FrameSize::HUGE as usize;
+    const FRAME_SIZE: FrameSize = FrameSize::HUGE;
+}
+
+// Page (virtual) and PhysFrame (physical) only differ in their address type.
+macro_rules! page_type {
+    ($name:ident, $range:ident, $range_inclusive:ident, $address:ident) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct $name<S: PageSize = Size4KiB> {
+            start_address: $address,
+            size: PhantomData<S>,
+        }
+
+        impl<S: PageSize> $name<S> {
+            pub fn containing_address(address: $address) -> $name<S> {
+                $name { start_address: address.align_down(S::SIZE), size: PhantomData }
+            }
+
+            pub fn from_start_address(address: $address) -> Option<$name<S>> {
+                if address.is_aligned(S::SIZE) {
+                    Some($name { start_address: address, size: PhantomData })
+                } else {
+                    None
+                }
+            }
+
+            pub fn start_address(&self) -> $address {
+                self.start_address
+            }
+
+            pub fn size(&self) -> usize {
+                S::SIZE
+            }
+
+            // all pages from `start` up to, but without `end`
+            pub fn range(start: $name<S>, end: $name<S>) -> $range<S> {
+                $range { start, end }
+            }
+
+            pub fn range_inclusive(start: $name<S>, end: $name<S>) -> $range_inclusive<S> {
+                $range_inclusive { start, end, done: start > end }
+            }
+        }
+
+        // advances by `count` pages
+        impl<S: PageSize> Add<usize> for $name<S> {
+            type Output = $name<S>;
+
+            fn add(self, count: usize) -> $name<S> {
+                $name { start_address: self.start_address + count * S::SIZE, size: PhantomData }
+            }
+        }
+
+        #[derive(Clone, Copy, Debug)]
+        pub struct $range<S: PageSize = Size4KiB> {
+            pub start: $name<S>,
+            pub end: $name<S>,
+        }
+
+        impl<S: PageSize> Iterator for $range<S> {
+            type Item = $name<S>;
+
+            fn next(&mut self) -> Option<$name<S>> {
+                if self.start < self.end {
+                    let page = self.start;
+                    self.start = self.start + 1;
+                    Some(page)
+                } else {
+                    None
+                }
+            }
+        }
+
+        // can't go past `end`, so the last page of the address space works as well
+        #[derive(Clone, Copy, Debug)]
+        pub struct $range_inclusive<S: PageSize = Size4KiB> {
+            pub start: $name<S>,
+            pub end: $name<S>,
+            done: bool,
+        }
+
+        impl<S: PageSize> Iterator for $range_inclusive<S> {
+            type Item = $name<S>;
+
+            fn next(&mut self) -> Option<$name<S>> {
+                if self.done {
+                    return None;
+                }
+                let page = self.start;
+                if self.start == self.end {
+                    self.done = true;
+                } else {
+                    self.start = self.start + 1;
+                }
+                Some(page)
+            }
+        }
+    };
+}
+
+page_type!(Page, PageRange, PageRangeInclusive, VirtualAddress);
+page_type!(PhysFrame, PhysFrameRange, PhysFrameRangeInclusive, PhysicalAddress);
+
+impl<S: PageSize> From<PhysFrame<S>> for Frame {
+    fn from(frame: PhysFrame<S>) -> Frame {
+        Frame { start_address: frame.start_address(), free: false, size: S::FRAME_SIZE }
+    }
+}
+
+#[os_test]
+fn mem_page_ranges() {
+    let page = Page::<Size4KiB>::containing_address(VirtualAddress::new(0xFFFF_C000_0000_1234));
+    assert_eq!(page.start_address().data(), 0xFFFF_C000_0000_1000);
+    assert!(Page::<Size2MiB>::from_start_address(page.start_address()).is_none());
+
+    assert_eq!(Page::range(page, page + 3).count(), 3);
+    assert_eq!(Page::range(page + 3, page).count(), 0);
+    assert_eq!(Page::range_inclusive(page, page + 3).last(), Some(page + 3));
+
+    let last = Page::<Size4KiB>::containing_address(VirtualAddress::new(usize::MAX));
+    assert_eq!(Page::range_inclusive(last, last).count(), 1);
+
+    let start = PhysFrame::<Size2MiB>::containing_address(PhysicalAddress::new(0x40_0000));
+    let frames = PhysFrame::range(start, start + 2);
+    assert_eq!(frames.map(|frame| frame.start_address().data()).sum::<usize>(), 0x40_0000 + 0x60_0000);
+}
@@ -1,11 +1,27 @@
+use bitflags::bitflags;
+use macros::os_test;
+
 use crate::mem::address::PhysicalAddress;
 
 // Flags are conveniently stored in all the irrelevant parts of the address,
 // so we can just "and out" the actual address (bits 12 - 51)
-const ADDRESS_MASK: usize = 0xFFFFFFFFFF000;
-const PRESENT_FLAG: usize = 0x1; // bit 0
-const WRITABLE_FLAG: usize = 0x2; // bit 1
-const IS_PAGE_FLAG: usize = 0x80; // bit 7
+pub const ADDRESS_MASK: usize = 0xFFFFFFFFFF000;
+
+bitflags! {
+    pub struct PageFlags: usize {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE = 1 << 4;
+        const ACCESSED = 1 << 5; // set by the CPU
+        const DIRTY = 1 << 6; // set by the CPU, only on pages
+        const HUGE_PAGE = 1 << 7; // PS bit, only valid in level 2 and 3 entries
+        const GLOBAL = 1 << 8;
+        const OWNED = 1 << 9; // ignored by the CPU, the address space allocated the frame and frees it
+        const NO_EXECUTE = 1 << 63; // needs EFER.NXE
+    }
+}
 
 #[repr(packed(8))]
 pub struct Entry(usize);
@@ -20,13 +36,38 @@ impl Entry {
     }
 
     pub fn is_present(&self) -> bool {
-        self.0 & PRESENT_FLAG > 0
+        self.flags().contains(PageFlags::PRESENT)
     }
 
-    pub fn set(&mut self, address: &PhysicalAddress, is_page: bool) {
-        self.0 = (address.data() & ADDRESS_MASK) + PRESENT_FLAG + WRITABLE_FLAG;
-        if is_page {
-            self.0 + IS_PAGE_FLAG;
-        }
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.0)
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
     }
+
+    // present is implied, everything else has to be passed explicitly
+    pub fn set(&mut self, address: &PhysicalAddress, flags: PageFlags) {
+        self.0 = (address.data() & ADDRESS_MASK) | (flags | PageFlags::PRESENT).bits();
+    }
+
+    // keeps the address, replaces all flags
+    pub fn update_flags(&mut self, flags: PageFlags) {
+        self.0 = (self.0 & ADDRESS_MASK) | flags.bits();
+    }
+}
+
+#[os_test]
+fn mem_paging_entry_flags() {
+    let mut entry = Entry(0);
+    assert!(!entry.is_present());
+
+    entry.set(&PhysicalAddress::new(0x20_0000), PageFlags::WRITABLE | PageFlags::HUGE_PAGE);
+    assert_eq!(entry.flags(), PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::HUGE_PAGE);
+    assert_eq!(entry.get_target_address().unwrap().data(), 0x20_0000);
+
+    entry.update_flags(PageFlags::PRESENT | PageFlags::NO_EXECUTE);
+    assert_eq!(entry.flags(), PageFlags::PRESENT | PageFlags::NO_EXECUTE);
+    assert_eq!(entry.get_target_address().unwrap().data(), 0x20_0000);
 }
@@ -0,0 +1,224 @@
+use core::arch::asm;
+
+use macros::os_test;
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
+use crate::boot::info::BootInfo;
+use crate::mem::frames::{FrameSize, FRAME_MAP};
+use crate::mem::paging::entry::PageFlags;
+use crate::mem::paging::five_level_paging;
+use crate::mem::allocator::HEAP_START;
+use crate::mem::paging::table::{HierarchicalLevel, ENTRY_COUNT, KERNEL_ENTRY_START, Level1, Level2, Table};
+use crate::mem::page::{Page, PhysFrame, Size2MiB, Size4KiB};
+use crate::mem::{address, GiB, KERNEL_OFFSET, PHYSICAL_OFFSET};
+
+// provided by linker.ld
+extern "C" {
+    static TEXT_START: u8;
+    static TEXT_END: u8;
+    static RODATA_START: u8;
+    static RODATA_END: u8;
+    static DATA_START: u8;
+    static DATA_END: u8;
+}
+
+struct Section {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: PageFlags,
+}
+
+impl Section {
+    fn contains(&self, page: &Page<Size4KiB>) -> bool {
+        page.start_address() >= self.start && page.start_address() < self.end.align_up(page.size())
+    }
+}
+
+fn symbol_address(symbol: &u8) -> VirtualAddress {
+    VirtualAddress::new(symbol as *const u8 as usize)
+}
+
+unsafe fn sections() -> [Section; 3] {
+    [
+        // RX
+        Section {
+            start: symbol_address(&TEXT_START),
+            end: symbol_address(&TEXT_END),
+            flags: PageFlags::empty(),
+        },
+        // R
+        Section {
+            start: symbol_address(&RODATA_START),
+            end: symbol_address(&RODATA_END),
+            flags: PageFlags::NO_EXECUTE,
+        },
+        // RW, includes .bss
+        Section {
+            start: symbol_address(&DATA_START),
+            end: symbol_address(&DATA_END),
+            flags: PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        },
+    ]
+}
+
+// Replaces the 1 GiB page from boot.s with 2 MiB pages, and the ones holding the kernel image with
+// 4 KiB pages, so every section gets its own permissions. Everything else stays RW, but NX.
+pub unsafe fn remap_kernel() {
+    let sections = sections();
+    let kernel_start = sections[0].start;
+    let kernel_end = sections[2].end;
+
+    let l2_frame = FRAME_MAP.lock().alloc_free();
+    let l2 = &mut *l2_frame.start_address.as_mut_ptr::<Table<Level2>>();
+
+    let first = Page::<Size2MiB>::containing_address(VirtualAddress::new(KERNEL_OFFSET));
+    for (index, large_page) in Page::range(first, first + ENTRY_COUNT).enumerate() {
+        let start = large_page.start_address();
+        if start + large_page.size() <= kernel_start || start >= kernel_end {
+            let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::HUGE_PAGE;
+            l2.set(index, &start.to_physical(), flags);
+            continue;
+        }
+
+        let l1_frame = FRAME_MAP.lock().alloc_free();
+        let l1 = &mut *l1_frame.start_address.as_mut_ptr::<Table<Level1>>();
+        let first_page = Page::<Size4KiB>::containing_address(start);
+        for (page_index, page) in Page::range(first_page, first_page + ENTRY_COUNT).enumerate() {
+            let flags = sections
+                .iter()
+                .find(|section| section.contains(&page))
+                .map(|section| section.flags)
+                .unwrap_or(PageFlags::WRITABLE | PageFlags::NO_EXECUTE);
+            l1.set(page_index, &page.start_address().to_physical(), flags);
+        }
+        l2.set(index, &l1_frame.start_address, PageFlags::WRITABLE);
+    }
+
+    // the new tables are a copy of the old mapping, so it is safe to switch over in one go
+    let kernel = VirtualAddress::new(KERNEL_OFFSET);
+    crate::with_active_root!(|root| {
+        let l3 = root.l4_mut(&kernel).unwrap().get_next_mut(kernel.l4_index()).unwrap();
+        l3.set(kernel.l3_index(), &l2_frame.start_address, PageFlags::WRITABLE);
+    });
+
+    asm!(
+        "mov %cr3, %rax",
+        "mov %rax, %cr3",
+        out("rax") _,
+        options(att_syntax),
+    );
+
+    crate::logln!(
+        "[paging] Mapped kernel sections from 0x{:X} to 0x{:X} ({} level paging).",
+        kernel_start.data(),
+        kernel_end.data(),
+        if five_level_paging() { 5 } else { 4 },
+    );
+}
+
+// Extends the physical memory window from boot.s (first GiB) up to the end of usable memory with
+// 2 MiB pages. Only RAM is cached, the holes in between may be MMIO and are mapped uncached. Where
+// RAM and a hole share a 2 MiB page, 4 KiB pages keep the RAM write back like its other mappings.
+pub unsafe fn map_physical_memory(boot_info: &BootInfo) {
+    let start = PhysFrame::<Size2MiB>::containing_address(PhysicalAddress::new(GiB));
+    let end = PhysFrame::<Size2MiB>::containing_address(PhysicalAddress::new(boot_info.memory_end()).align_up(start.size()));
+    assert!(
+        end.start_address().data() <= HEAP_START - PHYSICAL_OFFSET,
+        "Physical memory up to 0x{:X} does not fit below the heap.",
+        end.start_address().data(),
+    );
+
+    let window = VirtualAddress::new(PHYSICAL_OFFSET);
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+    let uncached = PageFlags::NO_CACHE | PageFlags::WRITE_THROUGH;
+    let is_ram = |start: PhysicalAddress, size: usize| boot_info.is_ram(start.data(), start.data() + size);
+
+    crate::with_active_root!(|root| {
+        for frame in PhysFrame::range(start, end) {
+            let target = window + frame.start_address().data();
+            let l3 = window_table(root.l4_mut(&target).unwrap(), target.l4_index());
+            let l2 = window_table(l3, target.l3_index());
+
+            if is_ram(frame.start_address(), frame.size()) {
+                l2.set(target.l2_index(), &frame.start_address(), flags | PageFlags::HUGE_PAGE);
+                continue;
+            }
+
+            let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+            let pages = PhysFrame::range(first, first + ENTRY_COUNT);
+            if !pages.clone().any(|page| is_ram(page.start_address(), page.size())) {
+                l2.set(target.l2_index(), &frame.start_address(), flags | uncached | PageFlags::HUGE_PAGE);
+                continue;
+            }
+
+            let l1 = window_table(l2, target.l2_index());
+            for (index, page) in pages.enumerate() {
+                let page_flags = if is_ram(page.start_address(), page.size()) { flags } else { flags | uncached };
+                l1.set(index, &page.start_address(), page_flags);
+            }
+        }
+    });
+
+    let window_end = end.start_address().data().max(GiB);
+    address::set_physical_window_end(window_end);
+
+    crate::logln!("[paging] Mapped physical memory up to 0x{:X} at 0x{:X}.", window_end, PHYSICAL_OFFSET);
+}
+
+// The next table of the window, which is created if it doesn't exist yet. Only the first GiB is
+// reachable until the window is extended, so new tables have to come from there.
+unsafe fn window_table<L: HierarchicalLevel>(table: &mut Table<L>, index: usize) -> &mut Table<L::NextLevel> {
+    if table.get_next(index).is_none() {
+        let frame = FRAME_MAP
+            .lock()
+            .alloc_contiguous(1, FrameSize::SMALL as usize, PhysicalAddress::new(GiB))
+            .expect("No frame below 1 GiB left for the physical memory window.")
+            .start
+            .start_address();
+        frame.as_mut_ptr::<u8>().write_bytes(0, FrameSize::SMALL as usize);
+        table.set(index, &frame, PageFlags::WRITABLE);
+    }
+    table.get_next_mut(index).unwrap()
+}
+
+// Address spaces copy the kernel half of the root table once, so all of its entries have to exist
+// up front. Costs a table per entry, but the kernel half never has to be synced afterwards.
+pub unsafe fn create_kernel_tables() {
+    crate::with_active_root!(|root| {
+        for index in KERNEL_ENTRY_START..ENTRY_COUNT {
+            root.get_or_create_next(index, PageFlags::WRITABLE);
+        }
+    });
+}
+
+#[cfg(test)]
+fn kernel_page_flags(address: usize) -> PageFlags {
+    let (_, flags, size) = crate::with_active_root!(|root| crate::mem::paging::mapper::translate(&VirtualAddress::new(address), root)).unwrap();
+    assert!(size == crate::mem::frames::FrameSize::SMALL, "Kernel is not mapped in 4 KiB pages.");
+    flags
+}
+
+#[os_test]
+fn mem_paging_kernel_permissions() {
+    static RODATA: u64 = 42;
+    static mut DATA: u64 = 0;
+
+    let text = kernel_page_flags(mem_paging_kernel_permissions as fn() as usize);
+    assert!(!text.contains(PageFlags::WRITABLE));
+    assert!(!text.contains(PageFlags::NO_EXECUTE));
+
+    let rodata = kernel_page_flags(&RODATA as *const u64 as usize);
+    assert!(!rodata.contains(PageFlags::WRITABLE));
+    assert!(rodata.contains(PageFlags::NO_EXECUTE));
+
+    let data = kernel_page_flags(core::ptr::addr_of!(DATA) as usize);
+    assert!(data.contains(PageFlags::WRITABLE));
+    assert!(data.contains(PageFlags::NO_EXECUTE));
+}
+
+#[os_test]
+fn mem_paging_physical_window_no_execute() {
+    // the kernel image is writable through the window, so it must not be executable there
+    let text = unsafe { symbol_address(&TEXT_START) }.to_physical().to_virtual();
+    let (_, flags, _) = crate::with_active_root!(|root| crate::mem::paging::mapper::translate(&text, root)).unwrap();
+    assert!(flags.contains(PageFlags::WRITABLE | PageFlags::NO_EXECUTE));
+}
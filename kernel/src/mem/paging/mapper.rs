@@ -1,54 +1,191 @@
 use core::arch::asm;
 
 use macros::os_test;
-use crate::mem::address::VirtualAddress;
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
 use crate::mem::frames::{Frame, FRAME_MAP, FrameSize};
-use crate::mem::paging::table::{Level4, Table};
+use crate::mem::paging::entry::{Entry, PageFlags};
+use crate::mem::paging::table::{RootLevel, Table};
 
-pub unsafe fn map_frame(frame: &Frame, target: &VirtualAddress, l4: &mut Table<Level4>) {
+// `flags` apply to the page itself, the PS bit is added for large and huge frames
+pub unsafe fn map_frame<R: RootLevel>(frame: &Frame, target: &VirtualAddress, flags: PageFlags, root: &mut Table<R>) {
+    assert!(try_map_frame(frame, target, flags, root), "Out of physical memory.");
+}
+
+// Like `map_frame`, but returns false if there is no frame left for a table it needs. Tables it
+// created on the way are freed again.
+pub unsafe fn try_map_frame<R: RootLevel>(frame: &Frame, target: &VirtualAddress, flags: PageFlags, root: &mut Table<R>) -> bool {
     assert_eq!(target.data() % frame.size as usize, 0);
 
-    crate::logln!(
+    // intermediate tables must not restrict what the page allows
+    let table_flags = PageFlags::WRITABLE | (flags & PageFlags::USER);
+
+    crate::debugln!(
         "[allocator] Mapping frame 0x{:X} to 0x{:X} with root table 0x{:X}.",
         frame.start_address.data(),
         target.data(),
-        l4 as *mut Table<Level4> as usize,
+        root as *mut Table<R> as usize,
     );
 
-    let l3 = l4.get_or_create_next(target.l4_index());
+    let mapped = map_leaf(frame, target, flags, table_flags, root).is_some();
+    if mapped {
+        flush(target);
+    } else {
+        free_empty_tables(target, root);
+    }
+    mapped
+}
+
+fn map_leaf<R: RootLevel>(
+    frame: &Frame,
+    target: &VirtualAddress,
+    flags: PageFlags,
+    table_flags: PageFlags,
+    root: &mut Table<R>,
+) -> Option<()> {
+    let l3 = root
+        .get_or_create_l4(target, table_flags)?
+        .try_get_or_create_next(target.l4_index(), table_flags)?;
 
     if frame.size == FrameSize::HUGE {
-        l3.set(target.l3_index(), &frame.start_address, true);
-        return;
+        l3.set(target.l3_index(), &frame.start_address, flags | PageFlags::HUGE_PAGE);
+    } else {
+        let l2 = l3.try_get_or_create_next(target.l3_index(), table_flags)?;
+
+        if frame.size == FrameSize::LARGE {
+            l2.set(target.l2_index(), &frame.start_address, flags | PageFlags::HUGE_PAGE);
+        } else {
+            l2.try_get_or_create_next(target.l2_index(), table_flags)?
+                // bit 7 is PAT on l1 pages, so never set it there
+                .set(target.l1_index(), &frame.start_address, flags - PageFlags::HUGE_PAGE);
+        }
     }
+    Some(())
+}
 
-    let l2 = l3.get_or_create_next(target.l3_index());
+// physical address, flags and page size `address` is mapped with, if it is mapped at all
+pub fn translate<R: RootLevel>(
+    address: &VirtualAddress,
+    root: &Table<R>,
+) -> Option<(PhysicalAddress, PageFlags, FrameSize)> {
+    let l3 = root.l4(address)?.get_next(address.l4_index())?;
+    let (entry, size) = if l3.is_page(address.l3_index()) {
+        (l3.get_entry(address.l3_index()), FrameSize::HUGE)
+    } else {
+        let l2 = l3.get_next(address.l3_index())?;
+        if l2.is_page(address.l2_index()) {
+            (l2.get_entry(address.l2_index()), FrameSize::LARGE)
+        } else {
+            (l2.get_next(address.l2_index())?.get_entry(address.l1_index()), FrameSize::SMALL)
+        }
+    };
 
-    if frame.size == FrameSize::LARGE {
-        l2.set(target.l2_index(), &frame.start_address, true);
-        return;
-    }
+    let offset = address.data() % size as usize;
+    entry
+        .get_target_address()
+        .map(|frame| (PhysicalAddress::new(frame.data() + offset), entry.flags(), size))
+}
+
+// removes the mapping of `address` and returns the frame it pointed to, which is not freed
+pub unsafe fn unmap<R: RootLevel>(address: &VirtualAddress, root: &mut Table<R>) -> Frame {
+    let (entry, size) = leaf_entry(address, root).expect("Unmapping address which is not mapped.");
+    let frame = Frame { start_address: entry.get_target_address().unwrap(), free: false, size };
+    entry.clear();
 
-    l2.get_or_create_next(target.l2_index())
-        // don't set is page flag on l1 pages
-        .set(target.l1_index(), &frame.start_address, false);
+    free_empty_tables(address, root);
+    flush(address);
+
+    crate::debugln!("[paging] Unmapped 0x{:X}.", address.data());
+
+    frame
+}
+
+// replaces the flags of the page `address` is in, keeping the page size
+pub unsafe fn update_flags<R: RootLevel>(address: &VirtualAddress, flags: PageFlags, root: &mut Table<R>) {
+    let (entry, size) = leaf_entry(address, root).expect("Updating flags of address which is not mapped.");
+    let flags = if size == FrameSize::SMALL {
+        flags - PageFlags::HUGE_PAGE
+    } else {
+        flags | PageFlags::HUGE_PAGE
+    };
+    // whoever owns the frame doesn't change with its permissions
+    entry.update_flags(flags | PageFlags::PRESENT | (entry.flags() & PageFlags::OWNED));
+
+    flush(address);
+}
+
+// boot.s identity maps the first GiB through the first root entry to get into the higher half. With
+// 5 levels, that entry points to the kernel's L4 table, so its first entry is an identity map too.
+pub unsafe fn unmap_identity() {
+    crate::with_active_root!(|root| {
+        if let Some(l4) = root.l4_mut(&VirtualAddress::new(0)) {
+            l4.clear(0);
+        }
+        root.clear(0);
+    });
 
-    // TODO: only flush affected pages
     asm!(
         "mov %cr3, %rax",
         "mov %rax, %cr3",
+        out("rax") _,
         options(att_syntax),
     )
 }
 
+fn leaf_entry<'table, R: RootLevel>(
+    address: &VirtualAddress,
+    root: &'table mut Table<R>,
+) -> Option<(&'table mut Entry, FrameSize)> {
+    let l3 = root.l4_mut(address)?.get_next_mut(address.l4_index())?;
+    if l3.is_page(address.l3_index()) {
+        return Some((l3.get_entry_mut(address.l3_index()), FrameSize::HUGE));
+    }
+
+    let l2 = l3.get_next_mut(address.l3_index())?;
+    if l2.is_page(address.l2_index()) {
+        return Some((l2.get_entry_mut(address.l2_index()), FrameSize::LARGE));
+    }
+
+    let entry = l2.get_next_mut(address.l2_index())?.get_entry_mut(address.l1_index());
+    if entry.is_present() {
+        Some((entry, FrameSize::SMALL))
+    } else {
+        None
+    }
+}
+
+fn free_empty_tables<R: RootLevel>(address: &VirtualAddress, root: &mut Table<R>) {
+    if let Some(l4) = root.l4_mut(address) {
+        if let Some(l3) = l4.get_next_mut(address.l4_index()) {
+            if let Some(l2) = l3.get_next_mut(address.l3_index()) {
+                if l2.get_next(address.l2_index()).map_or(false, |l1| l1.is_empty()) {
+                    l2.free_next(address.l2_index());
+                }
+                if l2.is_empty() {
+                    l3.free_next(address.l3_index());
+                }
+            }
+
+            if l3.is_empty() && !R::shares_l3(address) {
+                l4.free_next(address.l4_index());
+            }
+        }
+    }
+
+    R::free_empty_l4(root, address);
+}
+
+// drops the TLB entry of a single page, which also clears cached intermediate tables
+unsafe fn flush(address: &VirtualAddress) {
+    asm!("invlpg ({})", in(reg) address.data(), options(att_syntax, nostack));
+}
+
 #[os_test]
 fn mem_paging_mapper_map_frame() {
-    let table = Table::load_current();
     let frame = FRAME_MAP.lock().alloc_free();
-    let target = VirtualAddress::new(0x3000_0000_0000);
+    let target = VirtualAddress::new(0xFFFF_A000_0000_0000);
 
-    unsafe {
-        map_frame(&frame, &target, table);
+    crate::with_active_root!(|table| unsafe {
+        map_frame(&frame, &target, PageFlags::WRITABLE | PageFlags::NO_EXECUTE, table);
 
         let ptr = target.data() as *mut u8;
         let current = ptr.read();
@@ -57,5 +194,37 @@ fn mem_paging_mapper_map_frame() {
         assert_eq!(ptr.read(), 43);
 
         ptr.write(current);
-    }
+
+        let unmapped = unmap(&target, table);
+        FRAME_MAP.lock().free(unmapped);
+    });
+}
+
+#[os_test]
+fn mem_paging_mapper_unmap() {
+    let frame = FRAME_MAP.lock().alloc_free();
+    let frame_address = frame.start_address.data();
+    let target = VirtualAddress::new(0xFFFF_B000_0000_0000);
+
+    crate::with_active_root!(|table| unsafe {
+        map_frame(&frame, &target, PageFlags::WRITABLE | PageFlags::NO_EXECUTE, table);
+
+        let (address, flags, size) = translate(&VirtualAddress::new(target.data() + 0x123), table).unwrap();
+        assert_eq!(address.data(), frame_address + 0x123);
+        assert!(flags.contains(PageFlags::WRITABLE | PageFlags::NO_EXECUTE));
+        assert!(size == FrameSize::SMALL);
+
+        update_flags(&target, PageFlags::NO_EXECUTE, table);
+        let (_, flags, _) = translate(&target, table).unwrap();
+        assert!(!flags.contains(PageFlags::WRITABLE));
+
+        let unmapped = unmap(&target, table);
+        assert_eq!(unmapped.start_address.data(), frame_address);
+        assert!(translate(&target, table).is_none());
+        // nothing else lives in this part of the address space, so all tables below l3 are gone
+        let l3 = table.l4(&target).unwrap().get_next(target.l4_index()).unwrap();
+        assert!(!l3.get_entry(target.l3_index()).is_present());
+
+        FRAME_MAP.lock().free(unmapped);
+    });
 }
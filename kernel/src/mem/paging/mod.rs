@@ -1,3 +1,54 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::mem::address::PhysicalAddress;
+use crate::mem::paging::entry::ADDRESS_MASK;
+
 pub(crate) mod entry;
 pub(crate) mod table;
 pub(crate) mod mapper;
+pub(crate) mod kernel;
+pub(crate) mod space;
+
+// set once by `detect_paging_mode`, reading CR4 on every address check is too slow
+static FIVE_LEVEL_PAGING: AtomicBool = AtomicBool::new(false);
+
+// boot.s enables 5 level paging (CR4.LA57, bit 12) whenever the CPU supports it. Has to run before
+// anything else touches addresses or page tables.
+pub fn detect_paging_mode() {
+    let cr4: usize;
+    unsafe { asm!("mov %cr4, {}", out(reg) cr4, options(att_syntax, nomem, nostack)) };
+    FIVE_LEVEL_PAGING.store(cr4 & (1 << 12) != 0, Ordering::Relaxed);
+}
+
+pub fn five_level_paging() -> bool {
+    FIVE_LEVEL_PAGING.load(Ordering::Relaxed)
+}
+
+pub fn active_root() -> PhysicalAddress {
+    let address: usize;
+    unsafe { asm!("mov %cr3, {}", out(reg) address, options(att_syntax, nomem, nostack)) };
+    PhysicalAddress::new(address & ADDRESS_MASK)
+}
+
+// Evaluates `$body` with `$root` bound to the root table at `$address`, which is a Level5 or Level4
+// table depending on the paging mode, so generic paging code works with both.
+#[macro_export]
+macro_rules! with_root {
+    ($address:expr, |$root:ident| $body:expr) => {
+        if $crate::mem::paging::five_level_paging() {
+            let $root = $crate::mem::paging::table::Table::<$crate::mem::paging::table::Level5>::at(&$address);
+            $body
+        } else {
+            let $root = $crate::mem::paging::table::Table::<$crate::mem::paging::table::Level4>::at(&$address);
+            $body
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! with_active_root {
+    (|$root:ident| $body:expr) => {
+        $crate::with_root!($crate::mem::paging::active_root(), |$root| $body)
+    };
+}
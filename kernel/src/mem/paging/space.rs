@@ -0,0 +1,193 @@
+use core::arch::asm;
+use core::ops::Range;
+
+use macros::os_test;
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
+use crate::mem::frames::{Frame, FrameSize, FRAME_MAP};
+use crate::mem::paging::entry::{Entry, PageFlags};
+use crate::mem::paging::mapper::map_frame;
+use crate::mem::paging::{active_root, five_level_paging};
+use crate::mem::paging::table::{ENTRY_COUNT, KERNEL_ENTRY_START, Level1, Level2, Level3, Level4, Level5, RootLevel, Table};
+
+// A root table of its own for the lower half, while the kernel half is shared with all other spaces.
+pub struct AddressSpace {
+    root: PhysicalAddress,
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let frame = FRAME_MAP.lock().alloc_free();
+        let space = AddressSpace { root: frame.start_address };
+        crate::with_root!(space.root, |table| copy_kernel_half(table));
+
+        crate::debugln!("[paging] Created address space with root table 0x{:X}.", space.root.data());
+
+        space
+    }
+
+    // the root table is a Level4 or Level5 table, see `with_root!`
+    pub fn root(&self) -> &PhysicalAddress {
+        &self.root
+    }
+
+    pub fn is_active(&self) -> bool {
+        active_root().data() == self.root.data()
+    }
+
+    pub unsafe fn activate(&self) {
+        switch_to(&self.root);
+    }
+
+    // Maps a new frame at `target`, which belongs to the space and is freed with it. Frames mapped
+    // with `map_frame` directly stay with the caller.
+    pub unsafe fn map_new(&self, target: &VirtualAddress, flags: PageFlags) -> PhysicalAddress {
+        let frame = FRAME_MAP.lock().alloc_free();
+        crate::with_root!(self.root, |table| map_frame(&frame, target, flags | PageFlags::OWNED, table));
+        frame.start_address
+    }
+}
+
+impl Drop for AddressSpace {
+    // frees the pages the space owns in the lower half, the tables holding them and the root table
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Dropping the active address space.");
+
+        if five_level_paging() {
+            free_l5(Table::at(&self.root));
+        } else {
+            free_l4(Table::at(&self.root), 0..KERNEL_ENTRY_START);
+        }
+
+        crate::debugln!("[paging] Dropped address space with root table 0x{:X}.", self.root.data());
+
+        let root = PhysicalAddress::new(self.root.data());
+        FRAME_MAP.lock().free(Frame { start_address: root, free: true, size: FrameSize::SMALL });
+    }
+}
+
+// the kernel half is the same everywhere, so the kernel keeps running after the switch
+pub unsafe fn switch_to(root: &PhysicalAddress) {
+    asm!("mov {}, %cr3", in(reg) root.data(), options(att_syntax));
+}
+
+fn copy_kernel_half<R: RootLevel>(table: &mut Table<R>) {
+    let current = Table::<R>::load_current();
+    for index in 0..ENTRY_COUNT {
+        match current.get_entry(index).get_target_address() {
+            Some(address) if index >= KERNEL_ENTRY_START => table.set(index, &address, current.flags(index)),
+            _ => table.clear(index),
+        }
+    }
+}
+
+// MMIO, reserved memory or frames shared with other spaces are not the space's to free
+fn free_page(entry: &Entry, size: FrameSize) {
+    match entry.get_target_address() {
+        Some(address) if entry.flags().contains(PageFlags::OWNED) => {
+            FRAME_MAP.lock().free(Frame { start_address: address, free: true, size });
+        }
+        _ => {}
+    }
+}
+
+fn free_l5(l5: &mut Table<Level5>) {
+    for index in 0..KERNEL_ENTRY_START {
+        if let Some(l4) = l5.get_next_mut(index) {
+            free_l4(l4, 0..ENTRY_COUNT);
+            l5.free_next(index);
+        }
+    }
+}
+
+fn free_l4(l4: &mut Table<Level4>, indices: Range<usize>) {
+    for index in indices {
+        if let Some(l3) = l4.get_next_mut(index) {
+            free_l3(l3);
+            l4.free_next(index);
+        }
+    }
+}
+
+fn free_l3(l3: &mut Table<Level3>) {
+    for index in 0..ENTRY_COUNT {
+        if l3.is_page(index) {
+            free_page(l3.get_entry(index), FrameSize::HUGE);
+        } else if let Some(l2) = l3.get_next_mut(index) {
+            free_l2(l2);
+            l3.free_next(index);
+        }
+    }
+}
+
+fn free_l2(l2: &mut Table<Level2>) {
+    for index in 0..ENTRY_COUNT {
+        if l2.is_page(index) {
+            free_page(l2.get_entry(index), FrameSize::LARGE);
+        } else if let Some(l1) = l2.get_next_mut(index) {
+            free_l1(l1);
+            l2.free_next(index);
+        }
+    }
+}
+
+fn free_l1(l1: &mut Table<Level1>) {
+    for index in 0..ENTRY_COUNT {
+        free_page(l1.get_entry(index), FrameSize::SMALL);
+    }
+}
+
+#[os_test]
+fn mem_paging_space_switch() {
+    use crate::mem::paging::mapper::translate;
+
+    let frame = FRAME_MAP.lock().alloc_free();
+    let frame_address = PhysicalAddress::new(frame.start_address.data());
+    let target = VirtualAddress::new(0x1000_0000);
+    let previous = active_root();
+
+    let space = AddressSpace::new();
+    crate::with_active_root!(|current| crate::with_root!(space.root(), |table| {
+        for index in KERNEL_ENTRY_START..ENTRY_COUNT {
+            let kernel = current.get_entry(index).get_target_address().map(|address| address.data());
+            assert_eq!(table.get_entry(index).get_target_address().map(|address| address.data()), kernel);
+        }
+    }));
+
+    unsafe {
+        let flags = PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE;
+        crate::with_root!(space.root(), |table| map_frame(&frame, &target, flags, table));
+        assert!(crate::with_active_root!(|current| translate(&target, current).is_none()));
+
+        space.activate();
+        assert!(space.is_active());
+        let ptr = target.data() as *mut u64;
+        ptr.write(0xC0FFEE);
+        switch_to(&previous);
+    }
+
+    assert_eq!(unsafe { *frame_address.as_ptr::<u64>() }, 0xC0FFEE);
+
+    // the frame was mapped by the caller, so it is still theirs
+    drop(space);
+    assert!(!Frame::for_address(frame_address).free);
+    FRAME_MAP.lock().free(frame);
+}
+
+#[os_test]
+fn mem_paging_space_drop_owned_only() {
+    // VGA memory, reserved as part of low memory
+    let reserved = PhysicalAddress::new(0xB_8000);
+    let flags = PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE;
+
+    let space = AddressSpace::new();
+    let owned = unsafe {
+        let frame = Frame { start_address: reserved, free: false, size: FrameSize::SMALL };
+        crate::with_root!(space.root(), |table| map_frame(&frame, &VirtualAddress::new(0x1000_0000), flags, table));
+        space.map_new(&VirtualAddress::new(0x1000_1000), flags)
+    };
+    assert!(!Frame::for_address(owned).free);
+
+    drop(space);
+    assert!(!Frame::for_address(reserved).free);
+    assert!(Frame::for_address(owned).free);
+}
@@ -1,18 +1,22 @@
-use core::arch::asm;
 use core::marker::PhantomData;
-use crate::mem::paging::entry::Entry;
-use crate::mem::address::{PhysicalAddress};
-use crate::mem::frames::{FRAME_MAP};
+use crate::mem::paging::{active_root, five_level_paging};
+use crate::mem::paging::entry::{Entry, PageFlags};
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
+use crate::mem::frames::{Frame, FrameSize, FRAME_MAP};
 
-const ENTRY_COUNT: usize = 512;
+pub const ENTRY_COUNT: usize = 512;
+// first root table entry of the kernel half, which is shared by all address spaces
+pub const KERNEL_ENTRY_START: usize = ENTRY_COUNT / 2;
 
 pub trait TableLevel {}
 
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -22,6 +26,10 @@ pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
 
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
+
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -34,6 +42,77 @@ impl HierarchicalLevel for Level2 {
     type NextLevel = Level1;
 }
 
+// Level4 or Level5, depending on CR4.LA57. Lets the mapper get to the L4 table of an address
+// without caring about the paging mode.
+pub trait RootLevel: HierarchicalLevel + Sized {
+    const LEVELS: usize;
+
+    fn find_l4<'table>(root: &'table Table<Self>, address: &VirtualAddress) -> Option<&'table Table<Level4>>;
+    fn find_l4_mut<'table>(root: &'table mut Table<Self>, address: &VirtualAddress) -> Option<&'table mut Table<Level4>>;
+    // None if a new table is needed and there is no frame left for it
+    fn find_or_create_l4<'table>(
+        root: &'table mut Table<Self>,
+        address: &VirtualAddress,
+        flags: PageFlags,
+    ) -> Option<&'table mut Table<Level4>>;
+    // children of the root in the kernel half are shared between address spaces and never freed
+    fn free_empty_l4(root: &mut Table<Self>, address: &VirtualAddress);
+    fn shares_l3(address: &VirtualAddress) -> bool;
+}
+
+impl RootLevel for Level4 {
+    const LEVELS: usize = 4;
+
+    fn find_l4<'table>(root: &'table Table<Self>, _: &VirtualAddress) -> Option<&'table Table<Level4>> {
+        Some(root)
+    }
+
+    fn find_l4_mut<'table>(root: &'table mut Table<Self>, _: &VirtualAddress) -> Option<&'table mut Table<Level4>> {
+        Some(root)
+    }
+
+    fn find_or_create_l4<'table>(root: &'table mut Table<Self>, _: &VirtualAddress, _: PageFlags) -> Option<&'table mut Table<Level4>> {
+        Some(root)
+    }
+
+    fn free_empty_l4(_: &mut Table<Self>, _: &VirtualAddress) {}
+
+    fn shares_l3(address: &VirtualAddress) -> bool {
+        address.l4_index() >= KERNEL_ENTRY_START
+    }
+}
+
+impl RootLevel for Level5 {
+    const LEVELS: usize = 5;
+
+    fn find_l4<'table>(root: &'table Table<Self>, address: &VirtualAddress) -> Option<&'table Table<Level4>> {
+        root.get_next(address.l5_index())
+    }
+
+    fn find_l4_mut<'table>(root: &'table mut Table<Self>, address: &VirtualAddress) -> Option<&'table mut Table<Level4>> {
+        root.get_next_mut(address.l5_index())
+    }
+
+    fn find_or_create_l4<'table>(
+        root: &'table mut Table<Self>,
+        address: &VirtualAddress,
+        flags: PageFlags,
+    ) -> Option<&'table mut Table<Level4>> {
+        root.try_get_or_create_next(address.l5_index(), flags)
+    }
+
+    fn free_empty_l4(root: &mut Table<Self>, address: &VirtualAddress) {
+        let index = address.l5_index();
+        if index < KERNEL_ENTRY_START && root.get_next(index).map_or(false, |l4| l4.is_empty()) {
+            root.free_next(index);
+        }
+    }
+
+    fn shares_l3(_: &VirtualAddress) -> bool {
+        false
+    }
+}
+
 #[repr(C, align(4096))]
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -45,40 +124,90 @@ impl<L: TableLevel> Table<L> {
         &self.entries[index]
     }
 
-    pub fn set(&mut self, index: usize, address: &PhysicalAddress, is_page: bool) {
-        self.entries[index].set(address, is_page);
+    pub fn get_entry_mut(&mut self, index: usize) -> &mut Entry {
+        &mut self.entries[index]
+    }
+
+    // true if the entry maps a page instead of pointing to the next table
+    pub fn is_page(&self, index: usize) -> bool {
+        self.entries[index].is_present() && self.flags(index).contains(PageFlags::HUGE_PAGE)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.is_present())
+    }
+
+    pub fn set(&mut self, index: usize, address: &PhysicalAddress, flags: PageFlags) {
+        self.entries[index].set(address, flags);
+    }
+
+    pub fn flags(&self, index: usize) -> PageFlags {
+        self.entries[index].flags()
+    }
+
+    pub fn update_flags(&mut self, index: usize, flags: PageFlags) {
+        self.entries[index].update_flags(flags);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        self.entries[index].clear();
     }
 }
 
 impl <L: HierarchicalLevel> Table<L> {
-    pub fn create_next(&mut self, index: usize) -> &mut Table<L::NextLevel> {
-        let frame = FRAME_MAP.lock().alloc_free();
-        let ptr = frame.start_address.data() as *mut [u64; ENTRY_COUNT];
-        self.set(index, &frame.start_address, false);
+    pub fn create_next(&mut self, index: usize, flags: PageFlags) -> &mut Table<L::NextLevel> {
+        self.try_create_next(index, flags).expect("Out of physical memory.")
+    }
+
+    // None if there is no frame left for the table
+    pub fn try_create_next(&mut self, index: usize, flags: PageFlags) -> Option<&mut Table<L::NextLevel>> {
+        let frame = FRAME_MAP.lock().try_alloc_free()?;
+        let ptr = frame.start_address.as_mut_ptr::<[u64; ENTRY_COUNT]>();
+        self.set(index, &frame.start_address, flags);
 
         unsafe {
             ptr.as_mut().unwrap().fill(0);
-            (ptr as *mut Table<L::NextLevel>).as_mut().unwrap()
+            (ptr as *mut Table<L::NextLevel>).as_mut()
         }
     }
 
+    // removes the next table and gives its frame back, the caller has to flush the TLB
+    pub fn free_next(&mut self, index: usize) {
+        let address = self.entries[index].get_target_address().unwrap();
+        self.clear(index);
+        FRAME_MAP.lock().free(Frame { start_address: address, free: true, size: FrameSize::SMALL });
+    }
+
     pub fn get_next(&self, index: usize) -> Option<&Table<L::NextLevel>> {
+        if self.is_page(index) {
+            return None;
+        }
         self.entries[index]
             .get_target_address()
-            .map(|address| unsafe { &*(address.data() as *const Table<L::NextLevel>) })
+            .map(|address| unsafe { &*address.as_ptr::<Table<L::NextLevel>>() })
     }
 
     pub fn get_next_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
+        if self.is_page(index) {
+            return None;
+        }
         self.entries[index]
             .get_target_address()
-            .map(|address| unsafe { &mut *(address.data() as *mut Table<L::NextLevel>) })
+            .map(|address| unsafe { &mut *address.as_mut_ptr::<Table<L::NextLevel>>() })
+    }
+
+    // the CPU combines the flags of all levels, so existing entries are widened to allow `flags`
+    pub fn get_or_create_next(&mut self, index: usize, flags: PageFlags) -> &mut Table<L::NextLevel> {
+        self.try_get_or_create_next(index, flags).expect("Out of physical memory.")
     }
 
-    pub fn get_or_create_next(&mut self, index: usize) -> &mut Table<L::NextLevel> {
+    pub fn try_get_or_create_next(&mut self, index: usize, flags: PageFlags) -> Option<&mut Table<L::NextLevel>> {
         if self.entries[index].is_present() {
-            self.get_next_mut(index).unwrap()
+            let current = self.flags(index);
+            self.update_flags(index, current | flags);
+            self.get_next_mut(index)
         } else {
-            self.create_next(index)
+            self.try_create_next(index, flags)
         }
     }
 }
@@ -89,12 +218,26 @@ impl Table<Level1> {
     }
 }
 
-impl Table<Level4> {
-    pub fn load_current<'table>() -> &'table mut Table<Level4> {
-        let mut ptr: *mut Table<Level4>;
-        unsafe {
-            asm!("mov %cr3, {}", out(reg) ptr, options(att_syntax));
-            ptr.as_mut().unwrap()
-        }
+impl<R: RootLevel> Table<R> {
+    // only valid if `R` matches the paging mode, see `with_root!`
+    pub fn at<'table>(address: &PhysicalAddress) -> &'table mut Table<R> {
+        assert_eq!(R::LEVELS, if five_level_paging() { 5 } else { 4 }, "Root table level does not match paging mode.");
+        unsafe { &mut *address.as_mut_ptr::<Table<R>>() }
+    }
+
+    pub fn load_current<'table>() -> &'table mut Table<R> {
+        Table::at(&active_root())
+    }
+
+    pub fn l4(&self, address: &VirtualAddress) -> Option<&Table<Level4>> {
+        R::find_l4(self, address)
+    }
+
+    pub fn l4_mut(&mut self, address: &VirtualAddress) -> Option<&mut Table<Level4>> {
+        R::find_l4_mut(self, address)
+    }
+
+    pub fn get_or_create_l4(&mut self, address: &VirtualAddress, flags: PageFlags) -> Option<&mut Table<Level4>> {
+        R::find_or_create_l4(self, address, flags)
     }
 }
@@ -0,0 +1,180 @@
+/*
+Small allocations are served from slab caches instead of walking the heap's free list. A cache
+hands out objects of one size, carved from slabs it takes from the heap. Freed objects go on a list
+of their own, so both allocating and freeing are O(1). Slabs are never given back to the heap.
+
+`SIZE_CLASSES` takes every allocation of up to `MAX_CLASS_SIZE` bytes, larger ones go to the heap.
+Kernel objects that are created and dropped often can have a cache of their own:
+
+    static TASKS: Locked<SlabCache> = Locked::new(SlabCache::new("tasks", size_of::<Task>(), align_of::<Task>()));
+*/
+
+use core::alloc::{GlobalAlloc, Layout};
+use macros::os_test;
+
+use crate::mem::allocator::ALLOCATOR;
+use crate::mem::frames::FrameSize;
+use crate::util::locked::Locked;
+
+const SLAB_ALIGN: usize = FrameSize::SMALL as usize;
+// slabs are at least one page, but larger objects get bigger slabs
+const MIN_OBJECTS: usize = 8;
+
+const MIN_CLASS_SIZE: usize = 16;
+pub const MAX_CLASS_SIZE: usize = 2048;
+
+pub static SIZE_CLASSES: [Locked<SlabCache>; 8] = [
+    Locked::new(SlabCache::new("size-16", 16, 16)),
+    Locked::new(SlabCache::new("size-32", 32, 32)),
+    Locked::new(SlabCache::new("size-64", 64, 64)),
+    Locked::new(SlabCache::new("size-128", 128, 128)),
+    Locked::new(SlabCache::new("size-256", 256, 256)),
+    Locked::new(SlabCache::new("size-512", 512, 512)),
+    Locked::new(SlabCache::new("size-1024", 1024, 1024)),
+    Locked::new(SlabCache::new("size-2048", 2048, 2048)),
+];
+
+// The cache for `layout`, objects are aligned to their size so that covers the alignment as well.
+pub fn size_class(layout: Layout) -> Option<&'static Locked<SlabCache>> {
+    let size = layout.size().max(layout.align()).max(MIN_CLASS_SIZE).next_power_of_two();
+    if size > MAX_CLASS_SIZE {
+        return None;
+    }
+    Some(&SIZE_CLASSES[(size.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()) as usize])
+}
+
+// written into free objects
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    free: Option<&'static mut FreeObject>,
+    objects: usize,
+    used: usize,
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> SlabCache {
+        assert!(align.is_power_of_two() && align <= SLAB_ALIGN);
+        // every object has to hold a free list entry, and the next one has to be aligned as well
+        let align = if align < core::mem::align_of::<FreeObject>() { core::mem::align_of::<FreeObject>() } else { align };
+        let size = if size < core::mem::size_of::<FreeObject>() { core::mem::size_of::<FreeObject>() } else { size };
+        SlabCache {
+            name,
+            object_size: (size + align - 1) & !(align - 1),
+            free: None,
+            objects: 0,
+            used: 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    // objects in all slabs of this cache
+    pub fn objects(&self) -> usize {
+        self.objects
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn slab_size(&self) -> usize {
+        (self.object_size * MIN_OBJECTS + SLAB_ALIGN - 1) & !(SLAB_ALIGN - 1)
+    }
+
+    pub unsafe fn alloc(&mut self) -> *mut u8 {
+        if self.free.is_none() && !self.grow() {
+            return core::ptr::null_mut();
+        }
+
+        let object = self.free.take().unwrap();
+        self.free = object.next.take();
+        self.used += 1;
+        object as *mut FreeObject as *mut u8
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        assert!(self.used > 0, "Freed more objects than '{}' handed out.", self.name);
+
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: self.free.take() });
+        self.free = Some(&mut *object);
+        self.used -= 1;
+    }
+
+    // takes a new slab from the heap and puts all of its objects on the free list
+    unsafe fn grow(&mut self) -> bool {
+        let size = self.slab_size();
+        let slab = ALLOCATOR.alloc(Layout::from_size_align_unchecked(size, SLAB_ALIGN)) as usize;
+        if slab == 0 {
+            return false;
+        }
+
+        // backwards, so the objects are handed out in address order
+        let count = size / self.object_size;
+        for index in (0..count).rev() {
+            let object = (slab + index * self.object_size) as *mut FreeObject;
+            object.write(FreeObject { next: self.free.take() });
+            self.free = Some(&mut *object);
+        }
+        self.objects += count;
+
+        crate::debugln!("[slab] Cache '{}' grew to {} objects.", self.name, self.objects);
+        true
+    }
+}
+
+#[os_test]
+fn mem_slab_size_class() {
+    let class = |size, align| size_class(Layout::from_size_align(size, align).unwrap()).map(|cache| cache.lock().name());
+
+    assert_eq!(class(4, 4), Some("size-16"));
+    assert_eq!(class(17, 8), Some("size-32"));
+    assert_eq!(class(8, 256), Some("size-256"));
+    assert_eq!(class(2048, 8), Some("size-2048"));
+    assert_eq!(class(2049, 8), None);
+    assert_eq!(class(16, 4096), None);
+}
+
+#[os_test]
+fn mem_slab_named_cache() {
+    #[repr(align(64))]
+    struct Object([u8; 72]);
+    static OBJECTS: Locked<SlabCache> = Locked::new(SlabCache::new(
+        "test-objects",
+        core::mem::size_of::<Object>(),
+        core::mem::align_of::<Object>(),
+    ));
+
+    let mut cache = OBJECTS.lock();
+    assert_eq!(cache.object_size(), 128);
+
+    let mut objects = [core::ptr::null_mut(); 3 * MIN_OBJECTS];
+    for object in objects.iter_mut() {
+        *object = unsafe { cache.alloc() };
+        assert!(!object.is_null());
+        assert_eq!(*object as usize % 64, 0);
+    }
+    // spans more than one slab, and no object is handed out twice
+    assert!(cache.objects() >= objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        assert!(objects[index + 1..].iter().all(|other| (*other as usize).abs_diff(*object as usize) >= 128));
+    }
+
+    for object in objects.iter() {
+        unsafe { cache.dealloc(*object) };
+    }
+    assert_eq!(cache.used(), 0);
+    // the last object freed is the first one handed out again
+    assert_eq!(unsafe { cache.alloc() }, objects[objects.len() - 1]);
+}
@@ -46,35 +46,121 @@ The format of the Multiboot information structure (as defined so far) follows:
         +-------------------+
 */
 
+use macros::os_test;
+
+use crate::boot::info::{BootModule, MemoryKind};
+use crate::mem::address::{PhysicalAddress, VirtualAddress};
+
+pub const MULTIBOOT_MAGIC: u32 = 0x2BADB002;
+pub const MULTIBOOT2_MAGIC: u32 = 0x36D76289;
+
+const FLAG_MEMORY: u32 = 1 << 0;
+const FLAG_BOOT_DEVICE: u32 = 1 << 1;
+const FLAG_CMDLINE: u32 = 1 << 2;
+const FLAG_MODULES: u32 = 1 << 3;
+const FLAG_MEMORY_MAP: u32 = 1 << 6;
+const FLAG_DRIVES: u32 = 1 << 7;
+const FLAG_BOOT_LOADER_NAME: u32 = 1 << 9;
+const FLAG_APM_TABLE: u32 = 1 << 10;
+const FLAG_VBE: u32 = 1 << 11;
+const FLAG_FRAMEBUFFER: u32 = 1 << 12;
+
+// Everything but the flags is only valid if the matching flag is set, so the fields are only
+// reachable through the accessors below.
 #[repr(C, packed)]
 pub struct MultibootInfo {
     pub flags: u32,
-    pub mem_lower: u32,
-    pub mem_upper: u32,
-    pub boot_device: u32,
-    pub cmdline: u32,
-    pub mods_count: u32,
-    pub mods_addr: u32,
-    pub syms: [u8; 16],
-    pub mmap_size: u32,
-    pub mmap_addr: u32,
-    pub drives_length: u32,
-    pub drives_addr: u32,
-    pub config_table: u32,
-    pub boot_loader_name: u32,
-    pub apm_table: u32,
-    pub vbe_control_info: u32,
-    pub vbe_mode_info: u32,
-    pub vbe_mode: u16,
-    pub vbe_interface_seg: u16,
-    pub vbe_interface_off: u16,
-    pub vbe_interface_len: u16,
-    pub framebuffer_addr: u64,
-    pub framebuffer_pitch: u32,
-    pub framebuffer_width: u32,
-    pub framebuffer_height: u32,
-    pub framebuffer_bpp: u8,
-    pub framebuffer_type: u8,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u8; 16],
+    mmap_size: u32,
+    mmap_addr: u32,
+    drives_length: u32,
+    drives_addr: u32,
+    config_table: u32,
+    boot_loader_name: u32,
+    apm_table: u32,
+    vbe_control_info: u32,
+    vbe_mode_info: u32,
+    vbe_mode: u16,
+    vbe_interface_seg: u16,
+    vbe_interface_off: u16,
+    vbe_interface_len: u16,
+    framebuffer_addr: u64,
+    framebuffer_pitch: u32,
+    framebuffer_width: u32,
+    framebuffer_height: u32,
+    framebuffer_bpp: u8,
+    framebuffer_type: u8,
+    color_info: [u8; 6],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootDevice {
+    pub drive: u8,
+    pub partition: u8,
+    pub sub_partition: u8,
+    pub sub_sub_partition: u8,
+}
+
+#[repr(C)]
+pub struct ModuleEntry {
+    pub mod_start: u32,
+    pub mod_end: u32,
+    string: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+pub struct DriveEntry {
+    pub size: u32,
+    pub number: u8,
+    pub mode: u8,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+pub struct DriveIter {
+    current: usize,
+    end: usize,
+}
+
+#[repr(C, packed)]
+pub struct ApmTable {
+    pub version: u16,
+    pub code_segment: u16,
+    pub offset: u32,
+    pub code_segment_16: u16,
+    pub data_segment: u16,
+    pub flags: u16,
+    pub code_segment_length: u16,
+    pub code_segment_16_length: u16,
+    pub data_segment_length: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VbeInfo {
+    pub control_info: u32,
+    pub mode_info: u32,
+    pub mode: u16,
+    pub interface_segment: u16,
+    pub interface_offset: u16,
+    pub interface_length: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub address: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub kind: u8,
     pub color_info: [u8; 6],
 }
 
@@ -83,34 +169,178 @@ pub struct MemoryMapEntry {
     pub size: u32,
     pub base: u64,
     pub limit: u64,
-    pub kind: MemoryKind,
-}
-
-#[allow(dead_code)]
-#[derive(Debug, PartialEq, Clone, Copy)]
-#[repr(u32)]
-pub enum MemoryKind {
-    Unknown = 0,
-    Usable = 1,
-    Undefined = 2,
-    ACPI = 3,
-    Reserved = 4,
-    Damaged = 5,
+    kind: u32,
 }
 
+#[derive(Clone, Copy)]
 pub struct MemoryMapPointer {
     buffer_end: usize,
     pub entry: &'static MemoryMapEntry,
 }
 
 impl MultibootInfo {
-    pub unsafe fn memory_map(&self) -> MemoryMapPointer {
-        // TODO: check mmap flag
-        MemoryMapPointer {
-            buffer_end: (self.mmap_addr + self.mmap_size) as usize,
-            entry: &*(self.mmap_addr as *const MemoryMapEntry),
+    pub unsafe fn load(address: usize) -> &'static MultibootInfo {
+        &*PhysicalAddress::new(address).as_ptr::<MultibootInfo>()
+    }
+
+    fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    // lower and upper memory in KiB
+    pub fn memory_bounds(&self) -> Option<(u32, u32)> {
+        if self.has_flag(FLAG_MEMORY) {
+            Some((self.mem_lower, self.mem_upper))
+        } else {
+            None
+        }
+    }
+
+    pub fn boot_device(&self) -> Option<BootDevice> {
+        if !self.has_flag(FLAG_BOOT_DEVICE) {
+            return None;
+        }
+        let [sub_sub_partition, sub_partition, partition, drive] = self.boot_device.to_le_bytes();
+        Some(BootDevice { drive, partition, sub_partition, sub_sub_partition })
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        if self.has_flag(FLAG_CMDLINE) && self.cmdline != 0 {
+            unsafe { read_c_str(PhysicalAddress::new(self.cmdline as usize)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn module_entries(&self) -> &'static [ModuleEntry] {
+        if self.has_flag(FLAG_MODULES) {
+            unsafe {
+                core::slice::from_raw_parts(PhysicalAddress::new(self.mods_addr as usize).as_ptr(), self.mods_count as usize)
+            }
+        } else {
+            &[]
+        }
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = BootModule> {
+        self.module_entries().iter().map(|entry| BootModule {
+            start: entry.mod_start as usize,
+            end: entry.mod_end as usize,
+            string: entry.string().unwrap_or(""),
+        })
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryMapPointer> {
+        if !self.has_flag(FLAG_MEMORY_MAP) || (self.mmap_size as usize) < core::mem::size_of::<MemoryMapEntry>() {
+            return None;
+        }
+        let start = PhysicalAddress::new(self.mmap_addr as usize).to_virtual().data();
+        Some(MemoryMapPointer {
+            buffer_end: start + self.mmap_size as usize,
+            entry: unsafe { &*(start as *const MemoryMapEntry) },
+        })
+    }
+
+    pub fn drives(&self) -> Option<DriveIter> {
+        if self.has_flag(FLAG_DRIVES) {
+            let start = PhysicalAddress::new(self.drives_addr as usize).to_virtual().data();
+            Some(DriveIter {
+                current: start,
+                end: start + self.drives_length as usize,
+            })
+        } else {
+            None
         }
     }
+
+    pub fn boot_loader_name(&self) -> Option<&'static str> {
+        if self.has_flag(FLAG_BOOT_LOADER_NAME) && self.boot_loader_name != 0 {
+            unsafe { read_c_str(PhysicalAddress::new(self.boot_loader_name as usize)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn apm_table(&self) -> Option<&'static ApmTable> {
+        if self.has_flag(FLAG_APM_TABLE) {
+            Some(unsafe { &*PhysicalAddress::new(self.apm_table as usize).as_ptr::<ApmTable>() })
+        } else {
+            None
+        }
+    }
+
+    pub fn vbe_info(&self) -> Option<VbeInfo> {
+        if !self.has_flag(FLAG_VBE) {
+            return None;
+        }
+        Some(VbeInfo {
+            control_info: self.vbe_control_info,
+            mode_info: self.vbe_mode_info,
+            mode: self.vbe_mode,
+            interface_segment: self.vbe_interface_seg,
+            interface_offset: self.vbe_interface_off,
+            interface_length: self.vbe_interface_len,
+        })
+    }
+
+    pub fn framebuffer(&self) -> Option<FramebufferInfo> {
+        if !self.has_flag(FLAG_FRAMEBUFFER) {
+            return None;
+        }
+        Some(FramebufferInfo {
+            address: self.framebuffer_addr,
+            pitch: self.framebuffer_pitch,
+            width: self.framebuffer_width,
+            height: self.framebuffer_height,
+            bpp: self.framebuffer_bpp,
+            kind: self.framebuffer_type,
+            color_info: self.color_info,
+        })
+    }
+}
+
+impl ModuleEntry {
+    pub fn string(&self) -> Option<&'static str> {
+        if self.string == 0 {
+            None
+        } else {
+            unsafe { read_c_str(PhysicalAddress::new(self.string as usize)) }
+        }
+    }
+}
+
+impl DriveEntry {
+    // I/O ports used by the BIOS for this drive, the list is terminated with a zero
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        let header_size = core::mem::size_of::<DriveEntry>();
+        let start = self as *const DriveEntry as usize + header_size;
+        let count = (self.size as usize).saturating_sub(header_size) / 2;
+        (0..count)
+            .map(move |i| unsafe { ((start + i * 2) as *const u16).read_unaligned() })
+            .take_while(|port| *port != 0)
+    }
+}
+
+impl Iterator for DriveIter {
+    type Item = &'static DriveEntry;
+
+    fn next(&mut self) -> Option<&'static DriveEntry> {
+        if self.current + core::mem::size_of::<DriveEntry>() > self.end {
+            return None;
+        }
+        let entry = unsafe { &*(self.current as *const DriveEntry) };
+        if entry.size == 0 {
+            return None;
+        }
+        self.current += entry.size as usize;
+        Some(entry)
+    }
+}
+
+impl MemoryMapEntry {
+    pub fn kind(&self) -> MemoryKind {
+        MemoryKind::from(self.kind)
+    }
 }
 
 impl MemoryMapPointer {
@@ -130,3 +360,468 @@ impl MemoryMapPointer {
         }
     }
 }
+
+/*
+The Multiboot2 information structure is a small fixed header followed by a list of tags. Every tag
+starts on an 8 byte boundary with the same two fields and the list is terminated by an end tag:
+
+        +-------------------+
+0       | total_size        |
+4       | reserved          |
+        +-------------------+
+8       | type              |    (first tag)
+12      | size              |    (without padding)
+16      | ...               |
+        +-------------------+
+        | ...               |
+        +-------------------+
+        | type = 0          |    (end tag)
+        | size = 8          |
+        +-------------------+
+*/
+
+const TAG_ALIGN: usize = 8;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+#[repr(C)]
+pub struct Multiboot2Info {
+    pub total_size: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+pub struct TagHeader {
+    pub kind: u32,
+    pub size: u32,
+}
+
+pub enum Tag {
+    CommandLine(&'static str),
+    BootLoaderName(&'static str),
+    Module(&'static ModuleTag),
+    MemoryMap(&'static MemoryMapTag),
+    Framebuffer(&'static FramebufferTag),
+    ElfSections(&'static ElfSectionsTag),
+    AcpiOldRsdp(&'static RsdpTag),
+    AcpiNewRsdp(&'static RsdpTag),
+    Other(&'static TagHeader),
+}
+
+pub struct TagIter {
+    current: usize,
+    end: usize,
+}
+
+#[repr(C)]
+pub struct ModuleTag {
+    header: TagHeader,
+    pub mod_start: u32,
+    pub mod_end: u32,
+}
+
+#[repr(C)]
+pub struct MemoryMapTag {
+    header: TagHeader,
+    pub entry_size: u32,
+    pub entry_version: u32,
+}
+
+#[repr(C)]
+pub struct MemoryMapTagEntry {
+    pub base: u64,
+    pub length: u64,
+    kind: u32,
+    reserved: u32,
+}
+
+#[derive(Clone)]
+pub struct MemoryMapTagIter {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+#[repr(C)]
+pub struct FramebufferTag {
+    header: TagHeader,
+    pub framebuffer_addr: u64,
+    pub framebuffer_pitch: u32,
+    pub framebuffer_width: u32,
+    pub framebuffer_height: u32,
+    pub framebuffer_bpp: u8,
+    pub framebuffer_type: u8,
+    reserved: u16,
+    pub color_info: [u8; 6],
+}
+
+// GRUB uses 32 bit fields here, even though the specification lists them as 16 bit.
+#[repr(C)]
+pub struct ElfSectionsTag {
+    header: TagHeader,
+    pub num: u32,
+    pub entry_size: u32,
+    pub string_table_index: u32,
+}
+
+#[repr(C, packed)]
+pub struct ElfSection {
+    pub name: u32,
+    pub kind: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub address_align: u64,
+    pub entry_size: u64,
+}
+
+pub struct ElfSectionIter {
+    current: usize,
+    remaining: usize,
+    entry_size: usize,
+}
+
+// Contains a copy of the RSDP (version 1 for the old, version 2 for the new tag).
+#[repr(C)]
+pub struct RsdpTag {
+    header: TagHeader,
+}
+
+impl Multiboot2Info {
+    pub unsafe fn load(address: usize) -> &'static Multiboot2Info {
+        &*PhysicalAddress::new(address).as_ptr::<Multiboot2Info>()
+    }
+
+    pub fn tags(&self) -> TagIter {
+        let start = self as *const Multiboot2Info as usize;
+        TagIter {
+            current: start + core::mem::size_of::<Multiboot2Info>(),
+            end: start + self.total_size as usize,
+        }
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        self.tags().find_map(|tag| match tag {
+            Tag::CommandLine(cmdline) => Some(cmdline),
+            _ => None,
+        })
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static str> {
+        self.tags().find_map(|tag| match tag {
+            Tag::BootLoaderName(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = BootModule> {
+        self.tags().filter_map(|tag| match tag {
+            Tag::Module(module) => Some(BootModule {
+                start: module.mod_start as usize,
+                end: module.mod_end as usize,
+                string: module.string().unwrap_or(""),
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn memory_map(&self) -> Option<&'static MemoryMapTag> {
+        self.tags().find_map(|tag| match tag {
+            Tag::MemoryMap(memory_map) => Some(memory_map),
+            _ => None,
+        })
+    }
+
+    pub fn framebuffer(&self) -> Option<&'static FramebufferTag> {
+        self.tags().find_map(|tag| match tag {
+            Tag::Framebuffer(framebuffer) => Some(framebuffer),
+            _ => None,
+        })
+    }
+
+    pub fn elf_sections(&self) -> Option<&'static ElfSectionsTag> {
+        self.tags().find_map(|tag| match tag {
+            Tag::ElfSections(sections) => Some(sections),
+            _ => None,
+        })
+    }
+
+    // prefers the ACPI 2.0 RSDP if the loader provided both
+    pub fn rsdp(&self) -> Option<&'static RsdpTag> {
+        let mut old = None;
+        for tag in self.tags() {
+            match tag {
+                Tag::AcpiNewRsdp(rsdp) => return Some(rsdp),
+                Tag::AcpiOldRsdp(rsdp) => old = Some(rsdp),
+                _ => {}
+            }
+        }
+        old
+    }
+}
+
+impl TagHeader {
+    fn address(&self) -> usize {
+        self as *const TagHeader as usize
+    }
+
+    fn string(&self) -> Option<&'static str> {
+        let header_size = core::mem::size_of::<TagHeader>();
+        unsafe { read_str(self.address() + header_size, (self.size as usize).saturating_sub(header_size)) }
+    }
+
+    unsafe fn cast<T>(&self) -> &'static T {
+        &*(self.address() as *const T)
+    }
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        while self.current + core::mem::size_of::<TagHeader>() <= self.end {
+            let header = unsafe { &*(self.current as *const TagHeader) };
+            if header.kind == TAG_END {
+                return None;
+            }
+            // a broken tag would have the iterator go in circles or run off the end of the info
+            let size = header.size as usize;
+            if size < core::mem::size_of::<TagHeader>() || self.current + size > self.end {
+                self.current = self.end;
+                return None;
+            }
+            self.current = VirtualAddress::new(self.current + header.size as usize).align_up(TAG_ALIGN).data();
+
+            let tag = unsafe {
+                match header.kind {
+                    TAG_CMDLINE => header.string().map(Tag::CommandLine),
+                    TAG_BOOT_LOADER_NAME => header.string().map(Tag::BootLoaderName),
+                    TAG_MODULE => Some(Tag::Module(header.cast())),
+                    TAG_MEMORY_MAP => Some(Tag::MemoryMap(header.cast())),
+                    TAG_FRAMEBUFFER => Some(Tag::Framebuffer(header.cast())),
+                    TAG_ELF_SECTIONS => Some(Tag::ElfSections(header.cast())),
+                    TAG_ACPI_OLD => Some(Tag::AcpiOldRsdp(header.cast())),
+                    TAG_ACPI_NEW => Some(Tag::AcpiNewRsdp(header.cast())),
+                    _ => Some(Tag::Other(header.cast())),
+                }
+            };
+
+            // string tags which are not valid UTF-8 are skipped
+            if tag.is_some() {
+                return tag;
+            }
+        }
+        None
+    }
+}
+
+impl ModuleTag {
+    pub fn string(&self) -> Option<&'static str> {
+        let header_size = core::mem::size_of::<ModuleTag>();
+        let start = self as *const ModuleTag as usize + header_size;
+        unsafe { read_str(start, (self.header.size as usize).saturating_sub(header_size)) }
+    }
+}
+
+impl MemoryMapTag {
+    pub fn entries(&self) -> MemoryMapTagIter {
+        let start = self as *const MemoryMapTag as usize;
+        MemoryMapTagIter {
+            current: start + core::mem::size_of::<MemoryMapTag>(),
+            end: start + self.header.size as usize,
+            entry_size: self.entry_size as usize,
+        }
+    }
+}
+
+impl MemoryMapTagEntry {
+    pub fn kind(&self) -> MemoryKind {
+        MemoryKind::from(self.kind)
+    }
+}
+
+impl Iterator for MemoryMapTagIter {
+    type Item = &'static MemoryMapTagEntry;
+
+    fn next(&mut self) -> Option<&'static MemoryMapTagEntry> {
+        if self.entry_size == 0 || self.current + self.entry_size > self.end {
+            return None;
+        }
+        let entry = unsafe { &*(self.current as *const MemoryMapTagEntry) };
+        self.current += self.entry_size;
+        Some(entry)
+    }
+}
+
+impl ElfSectionsTag {
+    pub fn sections(&self) -> ElfSectionIter {
+        ElfSectionIter {
+            current: self as *const ElfSectionsTag as usize + core::mem::size_of::<ElfSectionsTag>(),
+            remaining: self.num as usize,
+            entry_size: self.entry_size as usize,
+        }
+    }
+}
+
+impl Iterator for ElfSectionIter {
+    type Item = &'static ElfSection;
+
+    fn next(&mut self) -> Option<&'static ElfSection> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let section = unsafe { &*(self.current as *const ElfSection) };
+        self.current += self.entry_size;
+        self.remaining -= 1;
+        Some(section)
+    }
+}
+
+impl RsdpTag {
+    // physical address of the RSDP copy
+    pub fn address(&self) -> usize {
+        VirtualAddress::new(self.bytes().as_ptr() as usize).to_physical().data()
+    }
+
+    pub fn bytes(&self) -> &'static [u8] {
+        let start = self as *const RsdpTag as usize + core::mem::size_of::<RsdpTag>();
+        let length = (self.header.size as usize).saturating_sub(core::mem::size_of::<RsdpTag>());
+        unsafe { core::slice::from_raw_parts(start as *const u8, length) }
+    }
+}
+
+// reads a zero terminated string without a known length
+pub(crate) unsafe fn read_c_str(address: PhysicalAddress) -> Option<&'static str> {
+    let start = address.to_virtual().data();
+    let mut length = 0;
+    while *((start + length) as *const u8) != 0 {
+        length += 1;
+    }
+    read_str(start, length)
+}
+
+// reads a zero terminated string of at most `max_length` bytes
+unsafe fn read_str(start: usize, max_length: usize) -> Option<&'static str> {
+    let bytes = core::slice::from_raw_parts(start as *const u8, max_length);
+    let length = bytes.iter().position(|byte| *byte == 0).unwrap_or(max_length);
+    core::str::from_utf8(&bytes[..length]).ok()
+}
+
+#[os_test]
+fn multiboot_info_flags() {
+    static CMDLINE: &[u8] = b"log=debug\0";
+    static MEMORY_MAP: [u32; 12] = [
+        20, 0x0, 0x0, 0x9FC00, 0x0, 1,
+        20, 0x100000, 0x0, 0x7EE0000, 0x0, 1,
+    ];
+
+    let physical = |address: usize| VirtualAddress::new(address).to_physical().data() as u32;
+
+    let mut info: MultibootInfo = unsafe { core::mem::zeroed() };
+    info.flags = FLAG_MEMORY | FLAG_CMDLINE | FLAG_MEMORY_MAP;
+    info.mem_lower = 639;
+    info.mem_upper = 130048;
+    info.cmdline = physical(CMDLINE.as_ptr() as usize);
+    info.mmap_addr = physical(MEMORY_MAP.as_ptr() as usize);
+    info.mmap_size = core::mem::size_of_val(&MEMORY_MAP) as u32;
+    // set, but not flagged as present
+    info.mods_count = 3;
+    info.boot_loader_name = physical(CMDLINE.as_ptr() as usize);
+
+    assert_eq!(info.memory_bounds(), Some((639, 130048)));
+    assert_eq!(info.cmdline(), Some("log=debug"));
+    assert!(info.boot_device().is_none());
+    assert!(info.modules().next().is_none());
+    assert!(info.boot_loader_name().is_none());
+    assert!(info.drives().is_none());
+    assert!(info.apm_table().is_none());
+    assert!(info.vbe_info().is_none());
+    assert!(info.framebuffer().is_none());
+
+    let first = info.memory_map().unwrap();
+    assert_eq!(first.entry.kind(), MemoryKind::Usable);
+    assert_eq!({ first.entry.limit }, 0x9FC00);
+    let second = first.next().unwrap();
+    assert_eq!({ second.entry.base }, 0x100000);
+    assert!(second.next().is_none());
+}
+
+#[cfg(test)]
+#[repr(C, align(8))]
+struct TagBuffer([u8; 104]);
+
+#[os_test]
+fn multiboot2_tags() {
+    let mut buffer = TagBuffer([0; 104]);
+    let mut write = |offset: usize, value: u32| {
+        buffer.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+    };
+
+    write(0, 104);
+    // command line tag
+    write(8, TAG_CMDLINE);
+    write(12, 8 + 10);
+    // memory map tag with two entries
+    write(32, TAG_MEMORY_MAP);
+    write(36, 16 + 2 * 24);
+    write(40, 24);
+    write(48 + 8, 0x9FC00);
+    write(48 + 16, 1);
+    write(72, 0x100000);
+    write(72 + 8, 0x7EE0000);
+    write(72 + 16, 1);
+    // end tag
+    write(96, TAG_END);
+    write(100, 8);
+    buffer.0[16..25].copy_from_slice(b"log=debug");
+
+    let address = VirtualAddress::new(buffer.0.as_ptr() as usize).to_physical();
+    let info = unsafe { Multiboot2Info::load(address.data()) };
+    assert_eq!(info.tags().count(), 2);
+    assert_eq!(info.cmdline(), Some("log=debug"));
+    assert!(info.rsdp().is_none());
+
+    let mut entries = info.memory_map().unwrap().entries();
+    assert_eq!({ entries.next().unwrap().length }, 0x9FC00);
+    let entry = entries.next().unwrap();
+    assert_eq!({ entry.base }, 0x100000);
+    assert_eq!(entry.kind(), MemoryKind::Usable);
+    assert!(entries.next().is_none());
+}
+
+#[os_test]
+fn multiboot2_tags_malformed() {
+    let mut buffer = TagBuffer([0; 104]);
+    let mut write = |offset: usize, value: u32| {
+        buffer.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+    };
+
+    write(0, 104);
+    write(8, TAG_CMDLINE);
+    write(12, 8 + 10);
+    // a tag without a size, as left by a truncated info block
+    write(32, TAG_MEMORY_MAP);
+    write(36, 0);
+    buffer.0[16..25].copy_from_slice(b"log=debug");
+
+    let address = VirtualAddress::new(buffer.0.as_ptr() as usize).to_physical();
+    let info = unsafe { Multiboot2Info::load(address.data()) };
+    assert_eq!(info.tags().count(), 1);
+
+    // and one that claims to reach past the end of the info
+    buffer.0[36..40].copy_from_slice(&200u32.to_le_bytes());
+    let info = unsafe { Multiboot2Info::load(address.data()) };
+    assert_eq!(info.tags().count(), 1);
+    assert!(info.memory_map().is_none());
+}
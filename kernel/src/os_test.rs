@@ -12,10 +12,22 @@ pub mod os_test {
     }
 
     pub fn test_runner(tests: &[&OSTest]) {
+        let filter = crate::boot::options::BOOT_OPTIONS.lock().test_filter;
+
         crate::logln!("[os_test] Running tests...");
         for test in tests {
+            if filter.map_or(false, |filter| !test.name.contains(filter)) {
+                crate::logln!("[os_test] {}... skipped", test.name);
+                continue;
+            }
             crate::logln!("[os_test] {}...", test.name);
+            let allocated = crate::mem::allocator::heap_stats().allocated;
             (test.test)();
+            // not a failure, some tests keep memory around on purpose
+            let leaked = crate::mem::allocator::heap_stats().allocated.saturating_sub(allocated);
+            if leaked > 0 {
+                crate::logln!("[os_test] {} kept {} bytes of heap memory.", test.name, leaked);
+            }
         }
         exit(true);
     }
@@ -0,0 +1,137 @@
+/*
+The PVH entry point gets a pointer to the hvm_start_info structure (all addresses are physical):
+
+        +-------------------+
+0       | magic             |    (0x336EC578)
+4       | version           |
+8       | flags             |
+12      | nr_modules        |
+16      | modlist_paddr     |
+24      | cmdline_paddr     |
+32      | rsdp_paddr        |
+        +-------------------+
+40      | memmap_paddr      |    (present if version >= 1)
+48      | memmap_entries    |    (present if version >= 1)
+52      | reserved          |
+        +-------------------+
+
+The memory map uses the E820 types, which are the same as the multiboot ones.
+*/
+
+use crate::boot::info::{BootModule, MemoryKind};
+use crate::mem::address::PhysicalAddress;
+use crate::multiboot::read_c_str;
+
+pub const PVH_MAGIC: u32 = 0x336EC578;
+
+#[repr(C)]
+pub struct StartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+pub struct ModuleEntry {
+    pub paddr: u64,
+    pub size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+#[repr(C)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub length: u64,
+    kind: u32,
+    reserved: u32,
+}
+
+#[derive(Clone)]
+pub struct MemoryMapIter {
+    entries: core::slice::Iter<'static, MemoryMapEntry>,
+}
+
+impl StartInfo {
+    pub unsafe fn load(address: usize) -> &'static StartInfo {
+        &*PhysicalAddress::new(address).as_ptr::<StartInfo>()
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        if self.cmdline_paddr == 0 {
+            None
+        } else {
+            unsafe { read_c_str(PhysicalAddress::new(self.cmdline_paddr as usize)) }
+        }
+    }
+
+    pub fn module_entries(&self) -> &'static [ModuleEntry] {
+        if self.modlist_paddr == 0 {
+            &[]
+        } else {
+            unsafe {
+                core::slice::from_raw_parts(PhysicalAddress::new(self.modlist_paddr as usize).as_ptr(), self.nr_modules as usize)
+            }
+        }
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = BootModule> {
+        self.module_entries().iter().map(|entry| BootModule {
+            start: entry.paddr as usize,
+            end: (entry.paddr + entry.size) as usize,
+            string: entry.cmdline(),
+        })
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryMapIter> {
+        if self.version < 1 || self.memmap_paddr == 0 {
+            return None;
+        }
+        let entries = unsafe {
+            core::slice::from_raw_parts(
+                PhysicalAddress::new(self.memmap_paddr as usize).as_ptr(),
+                self.memmap_entries as usize,
+            )
+        };
+        Some(MemoryMapIter { entries: entries.iter() })
+    }
+
+    pub fn rsdp(&self) -> Option<usize> {
+        if self.rsdp_paddr == 0 {
+            None
+        } else {
+            Some(self.rsdp_paddr as usize)
+        }
+    }
+}
+
+impl ModuleEntry {
+    pub fn cmdline(&self) -> &'static str {
+        if self.cmdline_paddr == 0 {
+            ""
+        } else {
+            unsafe { read_c_str(PhysicalAddress::new(self.cmdline_paddr as usize)).unwrap_or("") }
+        }
+    }
+}
+
+impl MemoryMapEntry {
+    pub fn kind(&self) -> MemoryKind {
+        MemoryKind::from(self.kind)
+    }
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = &'static MemoryMapEntry;
+
+    fn next(&mut self) -> Option<&'static MemoryMapEntry> {
+        self.entries.next()
+    }
+}
@@ -1,16 +1,28 @@
 SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
 SUCCESS_CODE=85 # QEMU shifts exit code by one
 
-mkdir -p $SCRIPT_DIR/target/isodir/boot/grub
-cp $1 $SCRIPT_DIR/target/isodir/boot/journey_os.bin
-cp $SCRIPT_DIR/grub.cfg $SCRIPT_DIR/target/isodir/boot/grub/grub.cfg
-grub-mkrescue -o $SCRIPT_DIR/target/journey_os.iso $SCRIPT_DIR/target/isodir
+# JOURNEY_OS_CPU=qemu64,+la57 boots with 5 level paging
+run_qemu() {
+  qemu-system-x86_64 \
+    "$@" \
+    -cpu ${JOURNEY_OS_CPU:-qemu64} \
+    -device VGA \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -serial stdio
+#    -monitor stdio \
+}
 
-qemu-system-x86_64 \
-  -cdrom $SCRIPT_DIR/target/journey_os.iso \
-  -device VGA \
-  -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
-  -serial stdio
-#  -monitor stdio \
+if [ -n "$JOURNEY_OS_GRUB" ]; then
+  # boot through GRUB (multiboot2), this needs grub-mkrescue to build an ISO on every run
+  mkdir -p $SCRIPT_DIR/target/isodir/boot/grub
+  cp $1 $SCRIPT_DIR/target/isodir/boot/journey_os.bin
+  cp $SCRIPT_DIR/grub.cfg $SCRIPT_DIR/target/isodir/boot/grub/grub.cfg
+  grub-mkrescue -o $SCRIPT_DIR/target/journey_os.iso $SCRIPT_DIR/target/isodir
+
+  run_qemu -cdrom $SCRIPT_DIR/target/journey_os.iso
+else
+  # QEMU loads the ELF itself and enters through the PVH note
+  run_qemu -kernel $1 -append "$JOURNEY_OS_CMDLINE"
+fi
 
 [ $? -eq $SUCCESS_CODE ] && exit 0 || exit 1